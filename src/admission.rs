@@ -0,0 +1,236 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::Duration;
+
+use crate::rpc::Request;
+
+/**
+ * Decides how many requests a server works on concurrently.
+ */
+pub trait Limit: Send {
+    /** Returns the number of requests which may be served concurrently. */
+    fn limit(&self) -> usize;
+
+    /** Records the latency of a request which was served. */
+    fn sample(&mut self, latency: Duration);
+}
+
+/**
+ * A limit which never changes.
+ */
+#[derive(Debug, Clone)]
+pub struct Fixed(pub usize);
+
+impl Limit for Fixed {
+    fn limit(&self) -> usize {
+        self.0
+    }
+
+    fn sample(&mut self, _latency: Duration) {}
+}
+
+/**
+ * A limit adapting to the latency of the requests, additive increase,
+ * multiplicative decrease: every request served within `timeout` raises the
+ * limit by one over the current limit, i.e. by one per round of requests,
+ * while every slower request scales it down by `backoff`.
+ */
+#[derive(Debug, Clone)]
+pub struct Aimd {
+    limit: f64,
+    min: usize,
+    max: usize,
+    timeout: Duration,
+    backoff: f64,
+}
+
+impl Aimd {
+    /** Creates a limit between `min` and `max` starting at `min`. */
+    pub fn new(min: usize, max: usize, timeout: Duration) -> Aimd {
+        assert!(0 < min && min <= max, "invalid limit range {}..{}", min, max);
+        Aimd {
+            limit: min as f64,
+            min,
+            max,
+            timeout,
+            backoff: 0.9,
+        }
+    }
+
+    /** Sets the factor by which slow requests scale down the limit. */
+    pub fn backoff(mut self, backoff: f64) -> Aimd {
+        assert!(0.0 < backoff && backoff < 1.0, "invalid backoff {}", backoff);
+        self.backoff = backoff;
+        self
+    }
+}
+
+impl Limit for Aimd {
+    fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    fn sample(&mut self, latency: Duration) {
+        let limit = if latency > self.timeout {
+            self.limit * self.backoff
+        } else {
+            self.limit + 1.0 / self.limit
+        };
+        self.limit = limit.max(self.min as f64).min(self.max as f64);
+    }
+}
+
+/**
+ * Admission control for a server session: requests beyond the concurrency
+ * limit wait in a queue of at most `max_pending` requests, and requests
+ * beyond that are rejected, to be nacked so that the client retries them
+ * elsewhere instead of waiting on an overloaded server.
+ *
+ * Like the `Lessor`, this does no I/O: the server offers every request it
+ * receives, starts the requests handed out by `poll_start`, and reports when
+ * they are done.
+ */
+pub struct AdmissionControl {
+    limit: Box<dyn Limit>,
+    max_pending: usize,
+    // The tags of the requests being served.
+    running: HashSet<u32>,
+    pending: VecDeque<(u32, Request)>,
+}
+
+impl AdmissionControl {
+    /** Serves at most `max_concurrent` requests concurrently. */
+    pub fn new(max_concurrent: usize, max_pending: usize) -> AdmissionControl {
+        AdmissionControl::with_limit(Fixed(max_concurrent), max_pending)
+    }
+
+    /** Serves as many requests concurrently as `limit` allows. */
+    pub fn with_limit<L: Limit + 'static>(limit: L, max_pending: usize) -> AdmissionControl {
+        AdmissionControl {
+            limit: Box::new(limit),
+            max_pending,
+            running: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /** Returns the number of requests being served. */
+    pub fn running(&self) -> usize {
+        self.running.len()
+    }
+
+    /** Returns the number of requests waiting to be served. */
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /**
+     * Offers a received request, returning it back if it is rejected;
+     * otherwise it is handed out by `poll_start` in turn.
+     */
+    pub fn offer(&mut self, tag: u32, req: Request) -> Result<(), (u32, Request)> {
+        let free = self.limit.limit().saturating_sub(self.running.len());
+        if self.pending.len() >= free + self.max_pending {
+            return Err((tag, req));
+        }
+        self.pending.push_back((tag, req));
+        Ok(())
+    }
+
+    /** Returns the next request to start serving, if the limit allows it. */
+    pub fn poll_start(&mut self) -> Option<(u32, Request)> {
+        if self.running.len() >= self.limit.limit() {
+            return None;
+        }
+        let next = self.pending.pop_front();
+        if let Some((tag, _)) = next {
+            self.running.insert(tag);
+        }
+        next
+    }
+
+    /**
+     * Records that the request with the given tag is done being served after
+     * `latency`. Does nothing if the request was cancelled.
+     */
+    pub fn finish(&mut self, tag: u32, latency: Duration) {
+        if self.running.remove(&tag) {
+            self.limit.sample(latency);
+        }
+    }
+
+    /**
+     * Drops the request with the given tag, e.g. because it was discarded,
+     * returning whether it was waiting or being served. A request which was
+     * being served frees its place without its latency being sampled, as it
+     * didn't complete.
+     */
+    pub fn cancel(&mut self, tag: u32) -> bool {
+        if self.running.remove(&tag) {
+            return true;
+        }
+        let before = self.pending.len();
+        self.pending.retain(|&(pending, _)| pending != tag);
+        self.pending.len() != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AdmissionControl, Aimd, Limit};
+    use crate::rpc::Request;
+
+    fn req() -> Request {
+        Request::new("/a".parse().unwrap(), vec![])
+    }
+
+    #[test]
+    fn test_admission() {
+        let mut admission = AdmissionControl::new(2, 1);
+        for tag in 2..5 {
+            assert!(admission.offer(tag, req()).is_ok());
+        }
+        assert_eq!(admission.offer(5, req()).err().map(|(tag, _)| tag), Some(5));
+
+        assert_eq!(admission.poll_start().map(|(tag, _)| tag), Some(2));
+        assert_eq!(admission.poll_start().map(|(tag, _)| tag), Some(3));
+        assert!(admission.poll_start().is_none());
+        assert_eq!((admission.running(), admission.pending()), (2, 1));
+
+        // A queued request can be cancelled, freeing its place in the queue.
+        assert!(admission.cancel(4));
+        assert!(!admission.cancel(4));
+        assert!(admission.offer(6, req()).is_ok());
+        admission.finish(2, Duration::from_millis(1));
+        assert_eq!(admission.poll_start().map(|(tag, _)| tag), Some(6));
+        assert!(admission.poll_start().is_none());
+
+        // So can a running request, without its latency counting.
+        assert!(admission.cancel(3));
+        assert_eq!(admission.running(), 1);
+        admission.finish(3, Duration::from_millis(1));
+        assert_eq!(admission.running(), 1);
+    }
+
+    #[test]
+    fn test_aimd() {
+        let timeout = Duration::from_millis(100);
+        let mut limit = Aimd::new(2, 4, timeout).backoff(0.5);
+        assert_eq!(limit.limit(), 2);
+        // 2 + 1/2 + 1/2.5 + 1/2.9
+        for _ in 0..3 {
+            limit.sample(timeout);
+        }
+        assert_eq!(limit.limit(), 3);
+        for _ in 0..10 {
+            limit.sample(timeout);
+        }
+        assert_eq!(limit.limit(), 4);
+
+        limit.sample(timeout * 2);
+        assert_eq!(limit.limit(), 2);
+        limit.sample(timeout * 2);
+        assert_eq!(limit.limit(), 2);
+    }
+}
@@ -0,0 +1,772 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{self, Instant};
+
+use crate::context::Broadcast;
+use crate::failure_detector::{self, FailureDetector, Status};
+use crate::naming::dtab::Dtab;
+use crate::rpc::{Error, Request, Response};
+use crate::transport::handshake::{HandshakeError, Registry};
+use crate::transport::mux_framer::Transport;
+use crate::transport::session::{Event, Session};
+
+/**
+ * Waits for the response to a request; the id identifies the call when the
+ * caller interrupts it.
+ */
+#[derive(Debug)]
+struct Caller {
+    id: u64,
+    reply: oneshot::Sender<Result<Response, Error>>,
+}
+
+/**
+ * A mux client. Every call is dispatched with its own tag, so any number of
+ * requests can be in flight on the session concurrently; each call returns a
+ * future resolving once the reply with the matching tag arrives.
+ *
+ * The client is only a handle: the session itself is driven by the
+ * `Dispatcher` returned alongside it, which has to be run on an executor.
+ */
+#[derive(Clone)]
+pub struct Client {
+    requests: mpsc::UnboundedSender<(Request, Caller)>,
+    interrupts: mpsc::UnboundedSender<(u64, String)>,
+    next_id: Arc<AtomicU64>,
+    status: watch::Receiver<Status>,
+}
+
+impl Client {
+    /**
+     * Creates a client over an established session (i.e. after the
+     * handshake), returning the dispatcher driving the session.
+     */
+    pub fn new<T: Transport>(transport: T) -> (Client, Dispatcher<T>) {
+        Client::build(transport, Session::client())
+    }
+
+    /**
+     * Creates a client which opens the session with a handshake offering the
+     * features in `registry`, returning the dispatcher driving the session.
+     * Calls made before the server answers are written once the session is
+     * established, fragmented as negotiated.
+     */
+    pub fn connect<T: Transport>(transport: T, registry: Registry) -> (Client, Dispatcher<T>) {
+        Client::build(transport, Session::connect(registry))
+    }
+
+    fn build<T: Transport>(transport: T, session: Session<Caller>) -> (Client, Dispatcher<T>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (interrupts_tx, interrupts_rx) = mpsc::unbounded_channel();
+        let (status_tx, status_rx) = watch::channel(Status::Open);
+        let dispatcher = Dispatcher {
+            transport,
+            session,
+            requests: rx,
+            interrupts: interrupts_rx,
+            pending: VecDeque::new(),
+            tags: HashMap::new(),
+            closed: false,
+            detector: None,
+            health: Status::Open,
+            lease: None,
+            status: status_tx,
+        };
+        let client = Client {
+            requests: tx,
+            interrupts: interrupts_tx,
+            next_id: Arc::new(AtomicU64::new(0)),
+            status: status_rx,
+        };
+        (client, dispatcher)
+    }
+
+    /**
+     * Returns the health of the session, which is `Closed` once the
+     * dispatcher is done.
+     */
+    pub fn status(&self) -> Status {
+        *self.status.borrow()
+    }
+
+    /** Returns whether both clients dispatch over the same session. */
+    pub fn same_session(&self, other: &Client) -> bool {
+        self.requests.same_channel(&other.requests)
+    }
+
+    /**
+     * Dispatches `req`, returning a future of its response. Dropping the
+     * future before it completes interrupts the call.
+     *
+     * The local dtab of the caller is sent before the dtab of the request,
+     * whose dentries take precedence, and so are the broadcast contexts of
+     * the caller before those of the request.
+     */
+    pub fn call(&self, mut req: Request) -> ResponseFuture {
+        let local = Dtab::local();
+        if !local.is_empty() {
+            req.dtab = local.concat(&req.dtab);
+        }
+        let mut contexts = Broadcast::current();
+        if !contexts.is_empty() {
+            contexts.extend(mem::take(&mut req.contexts));
+            req.contexts = contexts.into();
+        }
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // If the dispatcher is gone, `tx` is dropped with the request and the
+        // future resolves to `Error::Closed`.
+        let _ = self.requests.send((req, Caller { id, reply: tx }));
+        ResponseFuture {
+            inner: rx,
+            id,
+            interrupts: Some(self.interrupts.clone()),
+        }
+    }
+
+    /**
+     * Dispatches `req` like `call`, but interrupts the call with the reason
+     * "timeout" if no response arrived within `timeout`, failing it with
+     * `Error::Discarded`.
+     */
+    pub async fn call_with_timeout(&self,
+                                   req: Request,
+                                   timeout: Duration)
+                                   -> Result<Response, Error> {
+        let mut call = self.call(req);
+        match time::timeout(timeout, &mut call).await {
+            Ok(rep) => rep,
+            Err(_) => {
+                call.interrupt("timeout".to_string());
+                Err(Error::Discarded)
+            }
+        }
+    }
+}
+
+/**
+ * The response to a `Client::call`.
+ */
+pub struct ResponseFuture {
+    inner: oneshot::Receiver<Result<Response, Error>>,
+    id: u64,
+    // Taken once the call completed or was interrupted.
+    interrupts: Option<mpsc::UnboundedSender<(u64, String)>>,
+}
+
+impl ResponseFuture {
+    /**
+     * Interrupts the call, telling the server `why`: the request is
+     * discarded with a `Tdiscarded`, so that the server can stop working on
+     * it. Does nothing if the call already completed.
+     */
+    pub fn interrupt(mut self, why: String) {
+        if let Some(interrupts) = self.interrupts.take() {
+            let _ = interrupts.send((self.id, why));
+        }
+    }
+}
+
+impl Future for ResponseFuture {
+    type Output = Result<Response, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Response, Error>> {
+        let rep = match Pin::new(&mut self.inner).poll(cx) {
+            Poll::Ready(Ok(rep)) => rep,
+            Poll::Ready(Err(_)) => Err(Error::Closed),
+            Poll::Pending => return Poll::Pending,
+        };
+        self.interrupts = None;
+        Poll::Ready(rep)
+    }
+}
+
+impl Drop for ResponseFuture {
+    fn drop(&mut self) {
+        if let Some(interrupts) = self.interrupts.take() {
+            let _ = interrupts.send((self.id, "interrupted".to_string()));
+        }
+    }
+}
+
+/**
+ * Drives a client session: writes the requests of its `Client`s as
+ * `Tdispatch` messages and routes replies back by tag. `run` completes once
+ * all clients are dropped and every outstanding request got its reply, or
+ * when the session closes. A session drained by the server completes the
+ * same way once its outstanding requests are answered; calls made meanwhile
+ * fail with `Error::Draining`, so they can be retried on another session.
+ * Likewise, calls made once the lease granted by the server expired fail
+ * with `Error::Nack` without being sent.
+ */
+pub struct Dispatcher<T> {
+    transport: T,
+    session: Session<Caller>,
+    requests: mpsc::UnboundedReceiver<(Request, Caller)>,
+    interrupts: mpsc::UnboundedReceiver<(u64, String)>,
+    // Requests waiting for a tag to become available.
+    pending: VecDeque<(Request, Caller)>,
+    // The tags of the outstanding requests, by the id of their call.
+    tags: HashMap<u64, u32>,
+    closed: bool,
+    detector: Option<FailureDetector>,
+    // The verdict of the failure detector.
+    health: Status,
+    // When the lease granted by the server expires, if it granted one.
+    lease: Option<Instant>,
+    status: watch::Sender<Status>,
+}
+
+impl<T: Transport> Dispatcher<T> {
+    /**
+     * Pings the server to detect a dead session: once the failure detector
+     * closes the session, its outstanding requests fail.
+     */
+    pub fn with_failure_detector(mut self, config: failure_detector::Config) -> Self {
+        self.detector = Some(FailureDetector::new(config, Instant::now().into_std()));
+        self
+    }
+
+    /** Returns the number of requests waiting for their reply. */
+    pub fn outstanding(&self) -> usize {
+        self.session.outstanding()
+    }
+
+    /** Drives the session until it is done. */
+    pub async fn run(mut self) -> io::Result<()> {
+        let res = self.run_session().await;
+        self.fail_all();
+        self.status.send_replace(Status::Closed);
+        res
+    }
+
+    async fn run_session(&mut self) -> io::Result<()> {
+        loop {
+            self.process_events();
+            self.dispatch();
+            let deadline = match (self.check_liveness()?, self.lease_expiry()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            self.publish();
+            self.flush().await?;
+            let done = self.closed || self.session.is_draining();
+            if done && self.pending.is_empty() && self.session.outstanding() == 0 {
+                debug!("session done; drained={}", self.session.is_draining());
+                return Ok(());
+            }
+
+            // New requests are only taken once the pending ones got a tag.
+            let accepting = !self.closed && self.pending.is_empty();
+            let wakeup = deadline.unwrap_or_else(Instant::now);
+            tokio::select! {
+                _ = time::sleep_until(wakeup), if deadline.is_some() => {}
+                req = self.requests.recv(), if accepting => match req {
+                    Some(req) => self.pending.push_back(req),
+                    None => self.closed = true,
+                },
+                Some((id, why)) = self.interrupts.recv() => self.interrupt(id, why),
+                frame = self.transport.next() => match frame {
+                    Some(Ok(frame)) => self.session.handle(frame).map_err(invalid_data)?,
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        debug!("session closed by the server");
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /** Hands pending requests to the session for as long as there are tags available. */
+    fn dispatch(&mut self) {
+        while let Some((req, caller)) = self.pending.pop_front() {
+            // Interrupted before it was sent.
+            if caller.reply.is_closed() {
+                continue;
+            }
+            if self.lease_expired() {
+                let _ = caller.reply.send(Err(Error::Nack));
+                continue;
+            }
+            let id = caller.id;
+            match self.session.request(req, caller) {
+                Ok(tag) => {
+                    self.tags.insert(id, tag);
+                }
+                Err((req, caller)) if self.session.accepts_requests() => {
+                    // All tags are in flight; wait for a reply to free one.
+                    trace!("tags exhausted; pending={}", self.pending.len() + 1);
+                    self.pending.push_front((req, caller));
+                    return;
+                }
+                Err((_, caller)) => {
+                    let _ = caller.reply.send(Err(self.unsent_error()));
+                }
+            }
+        }
+    }
+
+    /**
+     * Discards the request of an interrupted call. Its tag stays in use until
+     * the server acknowledges the discard or replies after all.
+     */
+    fn interrupt(&mut self, id: u64, why: String) {
+        if let Some(&tag) = self.tags.get(&id) {
+            debug!("discarding request; tag={}, why={}", tag, why);
+            self.session.discard(tag, why);
+        }
+    }
+
+    fn process_events(&mut self) {
+        while let Some(event) = self.session.poll_event() {
+            match event {
+                Event::Established(negotiated) => {
+                    debug!("session established; window={:?}", negotiated.window);
+                    self.transport.set_window(negotiated.window.map(|w| w as usize));
+                }
+                Event::Response { data: caller, rep, .. } => {
+                    self.tags.remove(&caller.id);
+                    let _ = caller.reply.send(rep);
+                }
+                Event::Lease(how_long) => {
+                    trace!("leased for {:?}", how_long);
+                    self.lease = Some(Instant::now() + how_long);
+                }
+                Event::Pong => {
+                    if let Some(ref mut detector) = self.detector {
+                        detector.pong(Instant::now().into_std());
+                    }
+                }
+                event => debug!("ignoring session event {:?}", event),
+            }
+        }
+    }
+
+    /**
+     * Pings as the failure detector asks and records its verdict. Fails once
+     * the session is deemed dead; otherwise returns when to check back.
+     */
+    fn check_liveness(&mut self) -> io::Result<Option<Instant>> {
+        let detector = match self.detector {
+            Some(ref mut detector) => detector,
+            None => return Ok(None),
+        };
+        let now = Instant::now().into_std();
+        if detector.poll_ping(now) {
+            self.session.ping();
+        }
+        let status = detector.status(now);
+        let deadline = Instant::from_std(detector.next_deadline(now));
+        if status == Status::Closed {
+            warn!("closing session after missed pings");
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "session missed its pings"));
+        }
+        self.health = status;
+        Ok(Some(deadline))
+    }
+
+    /** Returns when the lease expires, unless it already did. */
+    fn lease_expiry(&self) -> Option<Instant> {
+        self.lease.filter(|&expires| expires > Instant::now())
+    }
+
+    /** Returns whether the server granted a lease which expired since. */
+    fn lease_expired(&self) -> bool {
+        self.lease.is_some() && self.lease_expiry().is_none()
+    }
+
+    /**
+     * Publishes the status of the session: draining overrides an expired
+     * lease, which overrides the failure detector.
+     */
+    fn publish(&self) {
+        let status = if self.session.is_draining() {
+            Status::Draining
+        } else if self.lease_expired() {
+            Status::Busy
+        } else {
+            self.health
+        };
+        self.status.send_if_modified(|current| {
+            let changed = *current != status;
+            *current = status;
+            changed
+        });
+    }
+
+    /** The error for requests which never made it to the wire. */
+    fn unsent_error(&self) -> Error {
+        if self.session.is_draining() {
+            Error::Draining
+        } else {
+            Error::Closed
+        }
+    }
+
+    /** Writes the messages emitted by the session. */
+    async fn flush(&mut self) -> io::Result<()> {
+        let mut sent = false;
+        while let Some(msg) = self.session.poll_message() {
+            self.transport.feed(msg).await?;
+            sent = true;
+        }
+        if sent {
+            self.transport.flush().await?;
+        }
+        Ok(())
+    }
+
+    /** Fails every outstanding and pending request. */
+    fn fail_all(&mut self) {
+        self.tags.clear();
+        for caller in self.session.close() {
+            let _ = caller.reply.send(Err(Error::Closed));
+        }
+        self.requests.close();
+        while let Ok(req) = self.requests.try_recv() {
+            self.pending.push_back(req);
+        }
+        while let Some((_, caller)) = self.pending.pop_front() {
+            let _ = caller.reply.send(Err(self.unsent_error()));
+        }
+    }
+}
+
+fn invalid_data(e: HandshakeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use futures::future;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::DuplexStream;
+    use tokio::sync::oneshot;
+    use tokio_util::codec::Framed;
+
+    use super::Client;
+    use crate::context::{self, Context};
+    use crate::failure_detector::{self, Status};
+    use crate::naming::dtab::Dtab;
+    use crate::rpc::{Error, Request, Response};
+    use crate::server::serve;
+    use crate::transport::message::Message;
+    use crate::transport::mux_framer::MuxCodec;
+
+    /**
+     * A server which answers every `Tdispatch` with its body reversed, failing
+     * requests for `/fail`. Replies are sent once all `n` requests arrived, in
+     * reverse order.
+     */
+    async fn reverse<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(io: T, n: usize) {
+        let mut framed = Framed::new(io, MuxCodec::new());
+        let mut replies = vec![];
+        while replies.len() < n {
+            match framed.next().await {
+                Some(Ok(Ok(Message::Tdispatch { tag, dst, mut req, .. }))) => {
+                    replies.push(if dst.to_string() == "/fail" {
+                        Message::rdispatch_error(tag, "failed".to_string())
+                    } else {
+                        req.reverse();
+                        Message::rdispatch_ok(tag, req)
+                    })
+                }
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        }
+        while let Some(reply) = replies.pop() {
+            framed.send(reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_calls() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let server = tokio::spawn(reverse(theirs, 3));
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        let a = client.call(Request::new("/a".parse().unwrap(), vec![1, 2, 3]));
+        let b = client.call(Request::new("/fail".parse().unwrap(), vec![]));
+        let c = client.call(Request::new("/c".parse().unwrap(), vec![4, 5]));
+        drop(client);
+
+        assert_eq!(a.await.unwrap().body, vec![3, 2, 1]);
+        match b.await {
+            Err(Error::Application(error)) => assert_eq!(error, "failed"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(c.await.unwrap().body, vec![5, 4]);
+        dispatcher.await.unwrap().unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failure_detector() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let config = failure_detector::Config {
+            min_period: Duration::from_secs(1),
+            max_missed: 2,
+            ..failure_detector::Config::default()
+        };
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.with_failure_detector(config).run());
+
+        // Answer the first ping only.
+        let mut server = Framed::new(theirs, MuxCodec::new());
+        match server.next().await {
+            Some(Ok(Ok(Message::Tping { tag }))) => {
+                server.send(Message::Rping { tag }).await.unwrap()
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        let call = client.call(Request::new("/a".parse().unwrap(), vec![]));
+        assert_eq!(client.status(), Status::Open);
+
+        let err = dispatcher.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.status(), Status::Closed);
+        assert!(matches!(call.await, Err(Error::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+        let mut server = Framed::new(theirs, MuxCodec::new());
+
+        let call = client.call(Request::new("/a".parse().unwrap(), vec![1]));
+        let tag = match server.next().await {
+            Some(Ok(Ok(Message::Tdispatch { tag, .. }))) => tag,
+            frame => panic!("unexpected frame {:?}", frame),
+        };
+        server.send(Message::Tdrain { tag: 1 }).await.unwrap();
+        match server.next().await {
+            Some(Ok(Ok(Message::Rdrain { tag: 1 }))) => {}
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        assert_eq!(client.status(), Status::Draining);
+        match client.call(Request::new("/b".parse().unwrap(), vec![])).await {
+            Err(Error::Draining) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // The outstanding request is still answered, then the session closes.
+        server.send(Message::rdispatch_ok(tag, vec![2])).await.unwrap();
+        assert_eq!(call.await.unwrap().body, vec![2]);
+        dispatcher.await.unwrap().unwrap();
+        assert_eq!(client.status(), Status::Closed);
+    }
+
+    /**
+     * Grants a lease, returning the status of the client once it saw the lease,
+     * i.e. once it answered a ping sent afterwards.
+     */
+    async fn lease(client: &Client,
+                   server: &mut Framed<DuplexStream, MuxCodec>,
+                   how_long: u64)
+                   -> Status {
+        server.send(Message::Tlease { unit: 0, how_long }).await.unwrap();
+        server.send(Message::Tping { tag: 1 }).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap().unwrap(), Message::Rping { tag: 1 });
+        client.status()
+    }
+
+    /** Makes a call the server answers, returning its result. */
+    async fn roundtrip(client: &Client,
+                       server: &mut Framed<DuplexStream, MuxCodec>)
+                       -> Result<Response, Error> {
+        let call = client.call(Request::new("/a".parse().unwrap(), vec![]));
+        let (rep, _) = futures::join!(call, async {
+            match server.next().await {
+                Some(Ok(Ok(Message::Tdispatch { tag, .. }))) => {
+                    server.send(Message::rdispatch_ok(tag, vec![])).await.unwrap()
+                }
+                frame => panic!("unexpected frame {:?}", frame),
+            }
+        });
+        rep
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        tokio::spawn(dispatcher.run());
+        let mut server = Framed::new(theirs, MuxCodec::new());
+
+        assert_eq!(lease(&client, &mut server, 1_000).await, Status::Open);
+        assert!(roundtrip(&client, &mut server).await.is_ok());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(client.status(), Status::Busy);
+
+        // Calls fail locally until the server grants a new lease.
+        let call = client.call(Request::new("/b".parse().unwrap(), vec![]));
+        assert!(matches!(call.await, Err(Error::Nack)));
+        assert_eq!(lease(&client, &mut server, 10_000).await, Status::Open);
+        assert!(roundtrip(&client, &mut server).await.is_ok());
+        assert_eq!(lease(&client, &mut server, 0).await, Status::Busy);
+        let call = client.call(Request::new("/b".parse().unwrap(), vec![]));
+        assert!(matches!(call.await, Err(Error::Nack)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interrupt() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+        let mut server = Framed::new(theirs, MuxCodec::new());
+
+        let call = client.call(Request::new("/a".parse().unwrap(), vec![]));
+        let a = match server.next().await {
+            Some(Ok(Ok(Message::Tdispatch { tag, .. }))) => tag,
+            frame => panic!("unexpected frame {:?}", frame),
+        };
+        drop(call);
+        let interrupted = Message::Tdiscarded {
+            which: a,
+            why: "interrupted".to_string(),
+        };
+        assert_eq!(server.next().await.unwrap().unwrap().unwrap(), interrupted);
+
+        let timeout = Duration::from_secs(1);
+        let call = client.call_with_timeout(Request::new("/b".parse().unwrap(), vec![]), timeout);
+        let (rep, frames) = futures::join!(call, async {
+            let mut frames = vec![];
+            for _ in 0..2 {
+                frames.push(server.next().await.unwrap().unwrap().unwrap());
+            }
+            frames
+        });
+        assert!(matches!(rep, Err(Error::Discarded)));
+        let b = frames[0].tag();
+        assert_ne!(a, b);
+        assert_eq!(frames[1],
+                   Message::Tdiscarded {
+                       which: b,
+                       why: "timeout".to_string(),
+                   });
+
+        // The tags are freed by the acknowledgement and by the late reply.
+        server.send(Message::Rdiscarded { tag: a }).await.unwrap();
+        server.send(Message::rdispatch_ok(b, vec![])).await.unwrap();
+        drop(client);
+        dispatcher.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_cancels_handler() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let (started_tx, started_rx) = oneshot::channel();
+        let (cancelled_tx, cancelled_rx) = oneshot::channel::<()>();
+        let channels = Mutex::new(Some((started_tx, cancelled_tx)));
+        // The handler never completes; dropping it drops `cancelled`.
+        let service = move |_| {
+            let (started, cancelled) = channels.lock().unwrap().take().unwrap();
+            let _ = started.send(());
+            async move {
+                let _cancelled = cancelled;
+                future::pending::<Result<Response, Error>>().await
+            }
+        };
+        let conn = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), service).run());
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        let call = client.call(Request::new("/a".parse().unwrap(), vec![]));
+        started_rx.await.unwrap();
+        call.interrupt("shutdown".to_string());
+        assert!(cancelled_rx.await.is_err());
+
+        drop(client);
+        dispatcher.await.unwrap().unwrap();
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_dtab() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let service = |req: Request| future::ok(Response::new(req.dtab.to_string().into_bytes()));
+        let server = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), service).run());
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        let mut req = Request::new("/a".parse().unwrap(), vec![]);
+        req.dtab = "/a=>/c".parse().unwrap();
+        let local = "/a=>/b".parse().unwrap();
+        let call = Dtab::with_local(local, async { client.call(req.clone()).await });
+        assert_eq!(call.await.unwrap().body, b"/a=>/b;/a=>/c");
+        assert_eq!(client.call(req).await.unwrap().body, b"/a=>/c");
+
+        drop(client);
+        dispatcher.await.unwrap().unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_contexts() {
+        struct Token(u8);
+
+        impl Context for Token {
+            const KEY: &'static str = "token";
+
+            fn marshal(&self) -> Vec<u8> {
+                vec![self.0]
+            }
+
+            fn unmarshal(bytes: &[u8]) -> Option<Token> {
+                bytes.first().map(|&b| Token(b))
+            }
+        }
+
+        // A server answering with the values of the contexts it received.
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let service = |req: Request| {
+            future::ok(Response::new(req.contexts.into_iter().flat_map(|(_, v)| v).collect()))
+        };
+        let server = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), service).run());
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        let mut req = Request::new("/a".parse().unwrap(), vec![]);
+        let call = context::with(&Token(1), async { client.call(req.clone()).await });
+        assert_eq!(call.await.unwrap().body, [1]);
+        // The contexts of the request take precedence.
+        req.contexts = vec![(b"token".to_vec(), vec![2]), (b"other".to_vec(), vec![3])];
+        let call = context::with(&Token(1), async { client.call(req.clone()).await });
+        assert_eq!(call.await.unwrap().body, [2, 3]);
+        assert_eq!(client.call(Request::new("/a".parse().unwrap(), vec![])).await.unwrap().body,
+                   []);
+
+        drop(client);
+        dispatcher.await.unwrap().unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (ours, _theirs) = tokio::io::duplex(1024);
+        let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        drop(dispatcher);
+        match client.call(Request::new("/a".parse().unwrap(), vec![])).await {
+            Err(Error::Closed) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use std::future::Future;
+use std::slice;
+
+use tokio::task::futures::TaskLocalFuture;
+
+/**
+ * A value broadcast along with requests: a client sends the contexts of the
+ * caller with every `Tdispatch`, and a server handles the request with the
+ * contexts it came with, so that they cross every service hop.
+ *
+ * On the wire, a context is its key followed by its marshalled value.
+ */
+pub trait Context: Sized {
+    /** The key of the context, e.g. `com.example.TenantId`. */
+    const KEY: &'static str;
+
+    fn marshal(&self) -> Vec<u8>;
+
+    /** Reads a marshalled value, or returns `None` if the bytes aren't one. */
+    fn unmarshal(bytes: &[u8]) -> Option<Self>;
+}
+
+tokio::task_local! {
+    static CURRENT: Broadcast;
+}
+
+/**
+ * A set of broadcast contexts, kept marshalled so that the contexts a server
+ * doesn't know are passed on as well.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Broadcast {
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Broadcast {
+    pub fn new() -> Broadcast {
+        Broadcast::default()
+    }
+
+    /**
+     * Returns the contexts of the current task, which are empty outside of
+     * `scope`. The contexts of a server handler are those its request came
+     * with.
+     */
+    pub fn current() -> Broadcast {
+        CURRENT.try_with(Broadcast::clone).unwrap_or_default()
+    }
+
+    /** Returns the value of `C`, if it is set and can be unmarshalled. */
+    pub fn get<C: Context>(&self) -> Option<C> {
+        let (_, value) = self.entries.iter().find(|(key, _)| key == C::KEY.as_bytes())?;
+        let value = C::unmarshal(value);
+        if value.is_none() {
+            warn!("invalid value for context {}", C::KEY);
+        }
+        value
+    }
+
+    /** Sets the value of `C`, replacing the current one. */
+    pub fn set<C: Context>(&mut self, value: &C) {
+        self.insert(C::KEY.as_bytes().to_vec(), value.marshal());
+    }
+
+    pub fn remove<C: Context>(&mut self) {
+        self.entries.retain(|(key, _)| key != C::KEY.as_bytes());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /** Iterates over the marshalled contexts, as keys and values. */
+    pub fn iter(&self) -> slice::Iter<'_, (Vec<u8>, Vec<u8>)> {
+        self.entries.iter()
+    }
+
+    /**
+     * Runs `f` with these contexts as the contexts of the current task, so
+     * that the requests it makes through a `Client` carry them. Tasks
+     * spawned by `f` don't inherit them.
+     */
+    pub fn scope<F: Future>(self, f: F) -> TaskLocalFuture<Broadcast, F> {
+        CURRENT.scope(self, f)
+    }
+
+    /** Calls `f` with these contexts as the contexts of the current task. */
+    pub fn sync_scope<F, R>(self, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        CURRENT.sync_scope(self, f)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+}
+
+/** Returns the value of `C` in the contexts of the current task. */
+pub fn get<C: Context>() -> Option<C> {
+    CURRENT.try_with(|contexts| contexts.get()).ok().flatten()
+}
+
+/** Runs `f` with `value` added to the contexts of the current task. */
+pub fn with<C, F>(value: &C, f: F) -> TaskLocalFuture<Broadcast, F>
+    where C: Context,
+          F: Future
+{
+    let mut contexts = Broadcast::current();
+    contexts.set(value);
+    contexts.scope(f)
+}
+
+/** Later contexts replace earlier ones of the same key. */
+impl Extend<(Vec<u8>, Vec<u8>)> for Broadcast {
+    fn extend<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl From<Vec<(Vec<u8>, Vec<u8>)>> for Broadcast {
+    fn from(contexts: Vec<(Vec<u8>, Vec<u8>)>) -> Broadcast {
+        let mut broadcast = Broadcast::new();
+        broadcast.extend(contexts);
+        broadcast
+    }
+}
+
+impl From<Broadcast> for Vec<(Vec<u8>, Vec<u8>)> {
+    fn from(contexts: Broadcast) -> Vec<(Vec<u8>, Vec<u8>)> {
+        contexts.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Broadcast, Context};
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct TenantId(String);
+
+    impl Context for TenantId {
+        const KEY: &'static str = "com.example.TenantId";
+
+        fn marshal(&self) -> Vec<u8> {
+            self.0.clone().into_bytes()
+        }
+
+        fn unmarshal(bytes: &[u8]) -> Option<TenantId> {
+            String::from_utf8(bytes.to_vec()).ok().map(TenantId)
+        }
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let mut contexts = Broadcast::new();
+        assert_eq!(contexts.get::<TenantId>(), None);
+        contexts.set(&TenantId("a".to_string()));
+        contexts.set(&TenantId("b".to_string()));
+        assert_eq!(contexts.len(), 1);
+        assert_eq!(contexts.get(), Some(TenantId("b".to_string())));
+
+        contexts.extend(vec![(b"other".to_vec(), vec![1]),
+                             (TenantId::KEY.as_bytes().to_vec(), vec![0xff])]);
+        assert_eq!(contexts.len(), 2);
+        assert_eq!(contexts.get::<TenantId>(), None);
+        contexts.remove::<TenantId>();
+        assert_eq!(Vec::from(contexts), vec![(b"other".to_vec(), vec![1])]);
+    }
+
+    #[tokio::test]
+    async fn test_scope() {
+        assert_eq!(super::get::<TenantId>(), None);
+        let tenant = TenantId("a".to_string());
+        let mut contexts = Broadcast::new();
+        contexts.extend(vec![(b"other".to_vec(), vec![1])]);
+        let current = contexts.scope(async {
+                                        super::with(&tenant, async { Broadcast::current() }).await
+                                    })
+            .await;
+        assert_eq!(current.len(), 2);
+        assert_eq!(current.get(), Some(tenant.clone()));
+        assert_eq!(Broadcast::new().sync_scope(super::get::<TenantId>), None);
+        assert!(Broadcast::current().is_empty());
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/**
+ * The health of a session. Whether it is open, suspect or closed is judged
+ * by its failure detector; whether it is busy or draining is up to the server.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /** Pings are answered in time. */
+    Open,
+    /**
+     * The outstanding ping takes unusually long, so the session may be in
+     * trouble; load balancers should prefer other sessions.
+     */
+    Suspect,
+    /**
+     * The lease granted by the server expired: it wants no new requests until
+     * it renews the lease, so load balancers should route them elsewhere.
+     */
+    Busy,
+    /**
+     * The server asked to drain the session: it takes no new requests and
+     * closes once the outstanding ones are answered.
+     */
+    Draining,
+    /** Pings went unanswered for too long, or the session is done. */
+    Closed,
+}
+
+/**
+ * The settings of a `FailureDetector`.
+ */
+#[derive(Debug, Clone)]
+pub struct Config {
+    /** The time between the start of consecutive pings. */
+    pub min_period: Duration,
+    /**
+     * The session is suspect once the outstanding ping takes `threshold`
+     * times as long as the slowest of the recent ones.
+     */
+    pub threshold: f64,
+    /** The number of recent round-trip times kept. */
+    pub window_size: usize,
+    /**
+     * The session is closed once a ping has been outstanding for this many
+     * periods.
+     */
+    pub max_missed: u32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            min_period: Duration::from_secs(5),
+            threshold: 2.0,
+            window_size: 100,
+            max_missed: 2,
+        }
+    }
+}
+
+/**
+ * Judges the liveness of a session from its pings, in the manner of
+ * Finagle's `ThresholdFailureDetector`: a ping is sent every period, and the
+ * round-trip times of the answers define how long the outstanding ping may
+ * take before the session is suspect.
+ *
+ * The detector does no I/O and has no clock of its own: the owner sends a
+ * ping whenever `poll_ping` says so, reports answers with `pong`, and checks
+ * back by `next_deadline`.
+ */
+pub struct FailureDetector {
+    config: Config,
+    rtts: VecDeque<Duration>,
+    // When the outstanding ping was sent.
+    sent: Option<Instant>,
+    next_ping: Instant,
+}
+
+impl FailureDetector {
+    /** Creates a detector which sends its first ping at `now`. */
+    pub fn new(config: Config, now: Instant) -> FailureDetector {
+        assert!(config.window_size > 0, "failure detector window must be positive");
+        FailureDetector {
+            rtts: VecDeque::with_capacity(config.window_size),
+            config,
+            sent: None,
+            next_ping: now,
+        }
+    }
+
+    /** Returns whether a ping is to be sent at `now`. */
+    pub fn poll_ping(&mut self, now: Instant) -> bool {
+        if self.sent.is_some() || now < self.next_ping {
+            return false;
+        }
+        self.sent = Some(now);
+        true
+    }
+
+    /** Reports the answer to the outstanding ping. */
+    pub fn pong(&mut self, now: Instant) {
+        let sent = match self.sent.take() {
+            Some(sent) => sent,
+            None => {
+                debug!("pong without outstanding ping");
+                return;
+            }
+        };
+        if self.rtts.len() == self.config.window_size {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(now.saturating_duration_since(sent));
+        self.next_ping = sent + self.config.min_period;
+    }
+
+    /** Returns the round-trip time of the last answered ping. */
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.rtts.back().cloned()
+    }
+
+    /** Returns the longest round-trip time of the recent pings. */
+    pub fn max_rtt(&self) -> Option<Duration> {
+        self.rtts.iter().max().cloned()
+    }
+
+    fn suspect_at(&self, sent: Instant) -> Option<Instant> {
+        self.max_rtt().map(|max| sent + max.mul_f64(self.config.threshold))
+    }
+
+    fn closed_at(&self, sent: Instant) -> Instant {
+        sent + self.config.min_period * self.config.max_missed
+    }
+
+    pub fn status(&self, now: Instant) -> Status {
+        let sent = match self.sent {
+            Some(sent) => sent,
+            None => return Status::Open,
+        };
+        if now >= self.closed_at(sent) {
+            Status::Closed
+        } else if self.suspect_at(sent).is_some_and(|at| now >= at) {
+            Status::Suspect
+        } else {
+            Status::Open
+        }
+    }
+
+    /**
+     * Returns when the detector next needs attention, i.e. when a ping is
+     * due or the status changes unless a pong arrives first.
+     */
+    pub fn next_deadline(&self, now: Instant) -> Instant {
+        match self.sent {
+            None => self.next_ping,
+            Some(sent) => {
+                match self.suspect_at(sent) {
+                    Some(at) if at > now => at.min(self.closed_at(sent)),
+                    _ => self.closed_at(sent),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Config, FailureDetector, Status};
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_ping_period() {
+        let start = Instant::now();
+        let config = Config {
+            min_period: ms(100),
+            ..Config::default()
+        };
+        let mut detector = FailureDetector::new(config, start);
+        assert!(detector.poll_ping(start));
+        // Only one ping is outstanding at a time.
+        assert!(!detector.poll_ping(start + ms(150)));
+        detector.pong(start + ms(10));
+        assert_eq!(detector.last_rtt(), Some(ms(10)));
+        assert_eq!(detector.next_deadline(start + ms(10)), start + ms(100));
+        assert!(!detector.poll_ping(start + ms(99)));
+        assert!(detector.poll_ping(start + ms(100)));
+    }
+
+    #[test]
+    fn test_status() {
+        let start = Instant::now();
+        let config = Config {
+            min_period: ms(100),
+            threshold: 2.0,
+            window_size: 2,
+            max_missed: 3,
+        };
+        let mut detector = FailureDetector::new(config, start);
+        // Without any round trip measured, only missed pings count.
+        assert!(detector.poll_ping(start));
+        assert_eq!(detector.next_deadline(start), start + ms(300));
+        detector.pong(start + ms(30));
+
+        let sent = start + ms(100);
+        assert!(detector.poll_ping(sent));
+        assert_eq!(detector.status(sent + ms(59)), Status::Open);
+        assert_eq!(detector.next_deadline(sent), sent + ms(60));
+        assert_eq!(detector.status(sent + ms(60)), Status::Suspect);
+        assert_eq!(detector.next_deadline(sent + ms(60)), sent + ms(300));
+        assert_eq!(detector.status(sent + ms(300)), Status::Closed);
+
+        // Slow pings raise the bar, until they leave the window.
+        detector.pong(sent + ms(80));
+        assert_eq!(detector.max_rtt(), Some(ms(80)));
+        let sent = start + ms(200);
+        assert!(detector.poll_ping(sent));
+        detector.pong(sent + ms(10));
+        assert_eq!(detector.max_rtt(), Some(ms(80)));
+        assert!(detector.poll_ping(start + ms(300)));
+        detector.pong(start + ms(310));
+        assert_eq!(detector.max_rtt(), Some(ms(10)));
+        assert_eq!(detector.status(start + ms(310)), Status::Open);
+    }
+}
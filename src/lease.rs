@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+/**
+ * Decides the leases a server grants the client of a session. A lease tells
+ * the client how long it may send requests; an expired lease asks it to
+ * route new requests elsewhere, which sheds load from a server before its
+ * requests start timing out.
+ *
+ * Like the `FailureDetector`, a lessor does no I/O and has no clock of its
+ * own: the server asks it for a lease whenever its load changes, and checks
+ * back by `next_deadline`.
+ */
+pub trait Lessor: Send {
+    /**
+     * Returns the lease to grant at `now`, given the number of requests in
+     * flight on the session, or `None` to leave the current lease alone. A
+     * lease of zero revokes the current one.
+     */
+    fn poll_lease(&mut self, in_flight: usize, now: Instant) -> Option<Duration>;
+
+    /** Returns when `poll_lease` should be called even if the load stays the same. */
+    fn next_deadline(&self, now: Instant) -> Option<Instant>;
+}
+
+/**
+ * A lessor granting leases of a fixed duration, renewed halfway through,
+ * while fewer than `max_in_flight` requests are in flight. Once the session
+ * has that many requests the lease is revoked, until they are served.
+ */
+#[derive(Debug, Clone)]
+pub struct QueueDepth {
+    max_in_flight: usize,
+    duration: Duration,
+    // When the lease granted last expires; `None` once revoked.
+    expires: Option<Instant>,
+}
+
+impl QueueDepth {
+    pub fn new(max_in_flight: usize, duration: Duration) -> QueueDepth {
+        assert!(max_in_flight > 0, "lessor queue depth must be positive");
+        QueueDepth {
+            max_in_flight,
+            duration,
+            expires: None,
+        }
+    }
+
+    fn renewal(&self, expires: Instant) -> Instant {
+        expires - self.duration / 2
+    }
+}
+
+impl Lessor for QueueDepth {
+    fn poll_lease(&mut self, in_flight: usize, now: Instant) -> Option<Duration> {
+        if in_flight >= self.max_in_flight {
+            return self.expires.take().map(|_| Duration::from_secs(0));
+        }
+        match self.expires {
+            Some(expires) if now < self.renewal(expires) => None,
+            _ => {
+                self.expires = Some(now + self.duration);
+                Some(self.duration)
+            }
+        }
+    }
+
+    fn next_deadline(&self, _now: Instant) -> Option<Instant> {
+        self.expires.map(|expires| self.renewal(expires))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Lessor, QueueDepth};
+
+    #[test]
+    fn test_queue_depth() {
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut lessor = QueueDepth::new(2, secs(10));
+        assert_eq!(lessor.next_deadline(start), None);
+
+        // The lease is granted, and renewed halfway through.
+        assert_eq!(lessor.poll_lease(0, start), Some(secs(10)));
+        assert_eq!(lessor.poll_lease(1, start + secs(4)), None);
+        assert_eq!(lessor.next_deadline(start), Some(start + secs(5)));
+        assert_eq!(lessor.poll_lease(1, start + secs(5)), Some(secs(10)));
+        assert_eq!(lessor.next_deadline(start), Some(start + secs(10)));
+
+        // The lease is revoked once, while the session is busy.
+        assert_eq!(lessor.poll_lease(2, start + secs(6)), Some(secs(0)));
+        assert_eq!(lessor.poll_lease(3, start + secs(7)), None);
+        assert_eq!(lessor.next_deadline(start), None);
+        assert_eq!(lessor.poll_lease(1, start + secs(8)), Some(secs(10)));
+    }
+}
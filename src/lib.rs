@@ -1,19 +1,62 @@
-extern crate byteorder;
-extern crate tokio_proto as proto;
+//! An implementation of the mux protocol, the session-layer protocol used by
+//! Finagle for multiplexed RPC.
+//!
+//! The `Message` type together with `encode` and `decode` makes up the wire
+//! protocol layer, which can be used on its own to build proxies and tools.
+//!
+//! `MuxCodec` frames messages on a byte stream, so that e.g. a
+//! `MuxFramed<TcpStream>` makes up the `Transport` of a `Client` or a server
+//! `Connection`. Unlike a `Framed<TcpStream, MuxCodec>`, it interleaves the
+//! fragments of large messages with the messages of other tags.
+//! `Client::connect` and `accept` open the session with a handshake, after
+//! which the transport fragments messages as negotiated.
+//!
+//! `Path`, `NameTree` and `Dtab` are the names carried by `Tdispatch`
+//! messages. They are read and written in Finagle's syntax, such as
+//! `/s/foo => /$/inet/127.0.0.1/8080 | ~`. A `Resolver` binds the destination
+//! of a request to addresses by delegation through its dtab.
+//!
+//! The local dtab and the broadcast contexts of the `context` module are
+//! sent with every request a `Client` makes, and restored around the
+//! handling of every request a `Connection` serves.
 
 #[macro_use]
 extern crate log;
 
+pub mod admission;
+mod client;
+pub mod context;
+pub mod failure_detector;
+pub mod lease;
+mod naming;
+mod pool;
+pub mod retry;
+mod rpc;
+mod server;
 mod transport;
 
-type Path = String;
+pub use client::{Client, Dispatcher, ResponseFuture};
+pub use naming::dtab::{Dentry, Dtab, Elem, Prefix};
+pub use naming::name_tree::{NameTree, Weighted};
+pub use naming::namer::{Bound, FileNamer, InetNamer, Namer};
+pub use naming::parse::{ParseError, ParseErrorKind};
+pub use naming::path::Path;
+pub use naming::resolver::{ResolveError, Resolver};
+pub use pool::Pool;
+pub use rpc::{Error, Request, Response};
+pub use server::{accept, serve, Connection, DrainHandle, Service};
 
-struct Dentry {
-    prefix: String,
-    dst: String,
-}
-
-type Dtab = Vec<Dentry>;
+pub use transport::handshake::{ClientHandshake, Feature, HandshakeError, Negotiated, Registry,
+                               ServerHandshake};
+pub use transport::message::{decode, decode_ref, encode, encode_into, encoded_len, tags, Contexts,
+                             DecodeError, DtabRef, Message, MessageRef};
+pub use transport::mux_framer::header::FrameSize;
+pub use transport::mux_framer::{Frame, MuxCodec, MuxFramed, Reassembler, Transport,
+                                WriteScheduler, DEFAULT_MAX_FRAME_SIZE,
+                                DEFAULT_MAX_PENDING_SIZE};
+pub use transport::session::{Event, Session};
+pub use transport::tag_map::TagMap;
+pub use transport::tls;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,330 @@
+use std::fmt;
+use std::future::Future;
+use std::slice;
+use std::str::FromStr;
+
+use tokio::task::futures::TaskLocalFuture;
+
+use crate::naming::name_tree::NameTree;
+use crate::naming::parse::{ParseError, Parser};
+use crate::naming::path::{show_elem, Path};
+
+/**
+ * An element of a dentry prefix: a label, or `*` matching any one element.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Elem {
+    Label(String),
+    Any,
+}
+
+/**
+ * The left-hand side of a dentry: a path whose elements may be `*`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Prefix {
+    elems: Vec<Elem>,
+}
+
+impl Prefix {
+    pub fn new(elems: Vec<Elem>) -> Prefix {
+        Prefix { elems }
+    }
+
+    /** Parses a prefix such as `/s/foo`, whose elements may be `*`. */
+    pub fn read(s: &str) -> Result<Prefix, ParseError> {
+        Parser::parse(s, Parser::prefix)
+    }
+
+    pub fn elems(&self) -> &[Elem] {
+        &self.elems
+    }
+
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
+    /** Returns whether `path` starts with this prefix. */
+    pub fn matches(&self, path: &Path) -> bool {
+        self.len() <= path.len() &&
+        self.elems.iter().zip(path.elems()).all(|(elem, label)| match *elem {
+            Elem::Label(ref l) => l == label,
+            Elem::Any => true,
+        })
+    }
+}
+
+impl From<Path> for Prefix {
+    fn from(path: Path) -> Prefix {
+        Prefix::new(path.elems().iter().cloned().map(Elem::Label).collect())
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.elems.is_empty() {
+            return write!(f, "/");
+        }
+        for elem in &self.elems {
+            write!(f, "/")?;
+            match *elem {
+                Elem::Label(ref label) => show_elem(f, label)?,
+                Elem::Any => write!(f, "*")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Prefix, ParseError> {
+        Prefix::read(s)
+    }
+}
+
+/**
+ * A delegation table entry rewriting paths starting with `prefix` to `dst`,
+ * written `prefix => dst`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dentry {
+    pub prefix: Prefix,
+    pub dst: NameTree<Path>,
+}
+
+impl Dentry {
+    pub fn new(prefix: Prefix, dst: NameTree<Path>) -> Dentry {
+        Dentry { prefix, dst }
+    }
+
+    /** Parses a dentry such as `/s => /$/inet | ~`. */
+    pub fn read(s: &str) -> Result<Dentry, ParseError> {
+        Parser::parse(s, Parser::dentry)
+    }
+}
+
+impl fmt::Display for Dentry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}=>{}", self.prefix, self.dst)
+    }
+}
+
+impl FromStr for Dentry {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Dentry, ParseError> {
+        Dentry::read(s)
+    }
+}
+
+tokio::task_local! {
+    static LOCAL: Dtab;
+}
+
+/**
+ * A delegation table: dentries separated by `;`, as carried by `Tdispatch`
+ * messages. Later dentries take precedence over earlier ones.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dtab {
+    dentries: Vec<Dentry>,
+}
+
+impl Dtab {
+    pub fn new(dentries: Vec<Dentry>) -> Dtab {
+        Dtab { dentries }
+    }
+
+    pub fn empty() -> Dtab {
+        Dtab::default()
+    }
+
+    /**
+     * Returns the local dtab of the current task, which is empty outside of
+     * `with_local`. The local dtab of a server handler is the one its
+     * request came with.
+     */
+    pub fn local() -> Dtab {
+        LOCAL.try_with(Dtab::clone).unwrap_or_default()
+    }
+
+    /**
+     * Runs `f` with `dtab` as the local dtab, so that the requests it makes
+     * through a `Client` carry `dtab` before their own. Tasks spawned by `f`
+     * don't inherit it.
+     */
+    pub fn with_local<F: Future>(dtab: Dtab, f: F) -> TaskLocalFuture<Dtab, F> {
+        LOCAL.scope(dtab, f)
+    }
+
+    /** Calls `f` with `dtab` as the local dtab, like `with_local`. */
+    pub fn with_local_sync<F, R>(dtab: Dtab, f: F) -> R
+        where F: FnOnce() -> R
+    {
+        LOCAL.sync_scope(dtab, f)
+    }
+
+    /** Parses a dtab such as `/s => /a; /s/foo => /b`. */
+    pub fn read(s: &str) -> Result<Dtab, ParseError> {
+        Parser::parse(s, Parser::dtab)
+    }
+
+    pub fn len(&self) -> usize {
+        self.dentries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dentries.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Dentry> {
+        self.dentries.iter()
+    }
+
+    pub fn push(&mut self, dentry: Dentry) {
+        self.dentries.push(dentry);
+    }
+
+    /** Returns this dtab followed by `other`, whose dentries take precedence. */
+    pub fn concat(&self, other: &Dtab) -> Dtab {
+        self.iter().chain(other.iter()).cloned().collect()
+    }
+
+    /**
+     * Rewrites `path` once: the destinations of the dentries whose prefix
+     * matches, each followed by the rest of the path, are tried in reverse
+     * order. Returns `Neg` if no prefix matches.
+     */
+    pub fn lookup(&self, path: &Path) -> NameTree<Path> {
+        let mut matches: Vec<_> = self.dentries
+            .iter()
+            .rev()
+            .filter(|dentry| dentry.prefix.matches(path))
+            .map(|dentry| {
+                let rest = path.drop(dentry.prefix.len());
+                dentry.dst.clone().map(&mut |dst: Path| dst.concat(&rest))
+            })
+            .collect();
+        match matches.len() {
+            0 => NameTree::Neg,
+            1 => matches.pop().unwrap(),
+            _ => NameTree::Alt(matches),
+        }
+    }
+}
+
+impl fmt::Display for Dtab {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, dentry) in self.dentries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ";")?;
+            }
+            write!(f, "{}", dentry)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Dtab {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Dtab, ParseError> {
+        Dtab::read(s)
+    }
+}
+
+impl FromIterator<Dentry> for Dtab {
+    fn from_iter<I: IntoIterator<Item = Dentry>>(iter: I) -> Dtab {
+        Dtab::new(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for Dtab {
+    type Item = Dentry;
+    type IntoIter = ::std::vec::IntoIter<Dentry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.dentries.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Dtab {
+    type Item = &'a Dentry;
+    type IntoIter = slice::Iter<'a, Dentry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.dentries.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dentry, Dtab, Elem, Prefix};
+    use crate::naming::name_tree::NameTree;
+    use crate::naming::path::Path;
+
+    #[test]
+    fn test_read() {
+        let dtab = Dtab::read("/s/foo => /$/inet/127.1/8080 | ~;").unwrap();
+        assert_eq!(dtab.len(), 1);
+        let dentry = dtab.iter().next().unwrap();
+        assert_eq!(dentry.prefix, Prefix::from(Path::read("/s/foo").unwrap()));
+        assert_eq!(dentry.dst,
+                   NameTree::Alt(vec![NameTree::Leaf(Path::read("/$/inet/127.1/8080").unwrap()),
+                                      NameTree::Neg]));
+
+        let dtab = Dtab::read(" /s/* => /a ; /=>! ").unwrap();
+        assert_eq!(dtab.iter().next().unwrap().prefix.elems(),
+                   [Elem::Label("s".to_string()), Elem::Any]);
+        assert_eq!(dtab.iter().nth(1).unwrap(), &Dentry::read("/ => !").unwrap());
+        assert!(Dtab::read("").unwrap().is_empty());
+        assert!(Dtab::read(" ; ").is_err());
+
+        let err = Dtab::read("/s/foo /b").unwrap_err();
+        assert_eq!(err.to_string(), "expected '=>' at offset 7: '/s/foo [/]b'");
+        let err = Dtab::read("/s => /a; /t =>").unwrap_err();
+        assert_eq!(err.offset(), 15);
+        let err = Dtab::read("/s => /a /t => /b").unwrap_err();
+        assert_eq!(err.to_string(), "expected end of input at offset 9: '/s => /a [/]t => /b'");
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dtab = Dtab::read("/s=>/a;/s/*/bar=>/b|~;/t=>/c").unwrap();
+        let lookup = |path: &str| dtab.lookup(&Path::read(path).unwrap()).to_string();
+        assert_eq!(lookup("/s/foo"), "/a/foo");
+        assert_eq!(lookup("/s/foo/bar/baz"), "(/b/baz | ~) | /a/foo/bar/baz");
+        assert_eq!(lookup("/s"), "/a");
+        assert_eq!(lookup("/u/foo"), "~");
+        assert!(Prefix::read("/s/*").unwrap().matches(&Path::read("/s/x/y").unwrap()));
+        assert!(!Prefix::read("/s/*").unwrap().matches(&Path::read("/s").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_local() {
+        assert!(Dtab::local().is_empty());
+        let dtab = Dtab::read("/s=>/a").unwrap();
+        let local = Dtab::with_local(dtab.clone(), async { Dtab::local() }).await;
+        assert_eq!(local, dtab);
+        assert_eq!(Dtab::with_local_sync(dtab.clone(), Dtab::local), dtab);
+        assert!(Dtab::local().is_empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let s = "/s/*/foo=>0.3*/a & 0.7*(/b | !);/=>~;/$/x\\x20y=>$";
+        let dtab = Dtab::read(s).unwrap();
+        assert_eq!(dtab.to_string(), s);
+        assert_eq!(Dtab::read(&dtab.to_string()).unwrap(), dtab);
+
+        let both = dtab.concat(&Dtab::read("/a=>/b").unwrap());
+        assert_eq!(both.len(), 4);
+        assert_eq!(both.to_string(), format!("{};/a=>/b", s));
+    }
+}
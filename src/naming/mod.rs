@@ -0,0 +1,6 @@
+pub mod dtab;
+pub mod name_tree;
+pub mod namer;
+pub mod parse;
+pub mod path;
+pub mod resolver;
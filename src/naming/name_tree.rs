@@ -0,0 +1,193 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::naming::parse::{ParseError, Parser};
+use crate::naming::path::Path;
+
+/**
+ * A tree of names, as on the right-hand side of a dentry: leaves are tried
+ * in order through alternation and combined by weight through union.
+ *
+ * The parser reads `/a | /b` as an `Alt` and `0.3 * /a & 0.7 * /b` as a
+ * `Union`; `~`, `!` and `$` are `Neg`, `Fail` and `Empty`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum NameTree<T> {
+    Leaf(T),
+    /** The first of the trees which does not resolve to negative. */
+    Alt(Vec<NameTree<T>>),
+    /** All of the trees, weighted. */
+    Union(Vec<Weighted<T>>),
+    /** No name at all, so that alternatives are tried. */
+    Neg,
+    /** A failure which stops resolution, without trying alternatives. */
+    Fail,
+    /** A name without any addresses. */
+    Empty,
+}
+
+// The weights of a tree are never NaN: `Weighted::new` rejects them.
+impl<T: Eq> Eq for NameTree<T> {}
+
+/**
+ * A tree in a union, with its share of the traffic relative to the others.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weighted<T> {
+    weight: f64,
+    pub tree: NameTree<T>,
+}
+
+impl<T> Weighted<T> {
+    /** The weight of union members written without one. */
+    pub const DEFAULT_WEIGHT: f64 = 1.0;
+
+    /**
+     * Weighs `tree` by `weight`.
+     *
+     * Panics if `weight` is NaN, which would make the tree unequal to itself.
+     */
+    pub fn new(weight: f64, tree: NameTree<T>) -> Weighted<T> {
+        assert!(!weight.is_nan(), "union weight is NaN");
+        Weighted { weight, tree }
+    }
+
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+}
+
+impl<T> NameTree<T> {
+    /** Replaces the leaves of the tree by the result of `f`. */
+    pub fn map<U, F>(self, f: &mut F) -> NameTree<U>
+        where F: FnMut(T) -> U
+    {
+        match self {
+            NameTree::Leaf(leaf) => NameTree::Leaf(f(leaf)),
+            NameTree::Alt(trees) => NameTree::Alt(trees.into_iter().map(|t| t.map(f)).collect()),
+            NameTree::Union(trees) => {
+                NameTree::Union(trees.into_iter()
+                                    .map(|w| Weighted { weight: w.weight, tree: w.tree.map(f) })
+                                    .collect())
+            }
+            NameTree::Neg => NameTree::Neg,
+            NameTree::Fail => NameTree::Fail,
+            NameTree::Empty => NameTree::Empty,
+        }
+    }
+}
+
+impl NameTree<Path> {
+    /** Parses a tree such as `/s/foo | ~`. */
+    pub fn read(s: &str) -> Result<NameTree<Path>, ParseError> {
+        Parser::parse(s, Parser::tree)
+    }
+}
+
+impl FromStr for NameTree<Path> {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<NameTree<Path>, ParseError> {
+        NameTree::read(s)
+    }
+}
+
+impl<T: fmt::Display> NameTree<T> {
+    /** Writes the tree in parentheses unless it is a leaf or a constant. */
+    fn fmt_simple(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NameTree::Alt(_) | NameTree::Union(_) => write!(f, "({})", self),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for NameTree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NameTree::Leaf(ref leaf) => write!(f, "{}", leaf),
+            NameTree::Alt(ref trees) => {
+                for (i, tree) in trees.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " | ")?;
+                    }
+                    // Unions bind tighter than alternation.
+                    match *tree {
+                        NameTree::Alt(_) => tree.fmt_simple(f)?,
+                        _ => write!(f, "{}", tree)?,
+                    }
+                }
+                Ok(())
+            }
+            NameTree::Union(ref trees) => {
+                for (i, weighted) in trees.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " & ")?;
+                    }
+                    // The weight of a lone tree tells it is a union.
+                    if trees.len() == 1 || weighted.weight != Weighted::<T>::DEFAULT_WEIGHT {
+                        write!(f, "{}*", weighted.weight)?;
+                    }
+                    weighted.tree.fmt_simple(f)?;
+                }
+                Ok(())
+            }
+            NameTree::Neg => write!(f, "~"),
+            NameTree::Fail => write!(f, "!"),
+            NameTree::Empty => write!(f, "$"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NameTree, Weighted};
+    use crate::naming::path::Path;
+
+    fn leaf(s: &str) -> NameTree<Path> {
+        NameTree::Leaf(Path::read(s).unwrap())
+    }
+
+    #[test]
+    fn test_read() {
+        assert_eq!(NameTree::read("/a").unwrap(), leaf("/a"));
+        assert_eq!(NameTree::read("/a | ~ | !").unwrap(),
+                   NameTree::Alt(vec![leaf("/a"), NameTree::Neg, NameTree::Fail]));
+        assert_eq!(NameTree::read("0.25*/a & /b | $").unwrap(),
+                   NameTree::Alt(vec![NameTree::Union(vec![Weighted::new(0.25, leaf("/a")),
+                                                            Weighted::new(1.0, leaf("/b"))]),
+                                      NameTree::Empty]));
+        assert_eq!(NameTree::read("2 * (/a | /b)").unwrap(),
+                   NameTree::Union(vec![Weighted::new(2.0,
+                                                      NameTree::Alt(vec![leaf("/a"),
+                                                                         leaf("/b")]))]));
+
+        let err = NameTree::read("/a | | /b").unwrap_err();
+        assert_eq!(err.to_string(),
+                   "expected a path or one of '!', '~', '$', '(' at offset 5: '/a | [|] /b'");
+        assert_eq!(NameTree::read("1.2.3*/a").unwrap_err().offset(), 0);
+        assert_eq!(NameTree::read("(/a").unwrap_err().offset(), 3);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for s in ["/a",
+                  "/a | ~ | !",
+                  "0.25*/a & /b | $",
+                  "/a & /b",
+                  "2*(/a | /b) & 0.5*(/c & /d)",
+                  "(/a | /b) | /c",
+                  "1*/a",
+                  "/s/\\x00"] {
+            let tree = NameTree::read(s).unwrap();
+            assert_eq!(tree.to_string(), s);
+            assert_eq!(NameTree::read(&tree.to_string()).unwrap(), tree);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "union weight is NaN")]
+    fn test_nan_weight() {
+        Weighted::new(f64::NAN, leaf("/a"));
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::naming::name_tree::NameTree;
+use crate::naming::path::Path;
+
+/**
+ * A name bound to the addresses serving it. `residual` is the rest of the
+ * path looked up, after the part identifying the addresses, e.g. `/users`
+ * for `/$/inet/127.0.0.1/8080/users`.
+ *
+ * A bound name is written as its id.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bound {
+    pub id: Path,
+    pub addrs: Vec<SocketAddr>,
+    pub residual: Path,
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
+/**
+ * Binds the paths under the prefix it is registered at with a `Resolver`.
+ *
+ * A namer looks up paths without that prefix, e.g. `/127.0.0.1/8080` for
+ * `/$/inet/127.0.0.1/8080`, and the ids of the names it binds are relative to
+ * it; the resolver prepends the prefix. Returns `Neg` for paths the namer
+ * doesn't know.
+ */
+pub trait Namer: Send + Sync {
+    fn lookup(&self, path: &Path) -> io::Result<NameTree<Bound>>;
+}
+
+/**
+ * Binds `/ip/port` paths to the address they spell out, conventionally
+ * registered at `/$/inet`.
+ *
+ * Only IP literals are bound, as `lookup` must not block on the system
+ * resolver: host names are negative.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InetNamer;
+
+impl Namer for InetNamer {
+    fn lookup(&self, path: &Path) -> io::Result<NameTree<Bound>> {
+        let (host, port) = match path.elems() {
+            [host, port, ..] => (host, port),
+            _ => return Ok(NameTree::Neg),
+        };
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return Ok(NameTree::Neg),
+        };
+        let ip = match host.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return Ok(NameTree::Neg),
+        };
+        Ok(NameTree::Leaf(Bound {
+                              id: Path::new(path.elems()[..2].to_vec()),
+                              addrs: vec![SocketAddr::new(ip, port)],
+                              residual: path.drop(2),
+                          }))
+    }
+}
+
+/**
+ * Binds names to the addresses listed for them in a file, which is read
+ * once. Each line holds a path followed by its addresses, e.g.
+ *
+ * ```text
+ * # The users service.
+ * /users 10.0.0.1:8080 10.0.0.2:8080
+ * ```
+ *
+ * A path is bound by the longest entry it starts with.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct FileNamer {
+    entries: Vec<(Path, Vec<SocketAddr>)>,
+}
+
+impl FileNamer {
+    /** Reads the entries of the file at `file`. */
+    pub fn load<P: AsRef<std::path::Path>>(file: P) -> io::Result<FileNamer> {
+        FileNamer::read(&fs::read_to_string(file)?)
+    }
+
+    /** Reads entries in the format of the file. */
+    pub fn read(s: &str) -> io::Result<FileNamer> {
+        let invalid = |n: usize, what: &dyn fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", n + 1, what))
+        };
+        let mut entries = Vec::new();
+        for (n, line) in s.lines().enumerate() {
+            // Paths may contain `#`, so comments start with a word.
+            let mut words = line.split_whitespace().take_while(|w| !w.starts_with('#'));
+            let path = match words.next() {
+                Some(path) => Path::read(path).map_err(|e| invalid(n, &e))?,
+                None => continue,
+            };
+            let addrs = words.map(|addr| addr.parse().map_err(|e| invalid(n, &e)))
+                             .collect::<io::Result<_>>()?;
+            entries.push((path, addrs));
+        }
+        Ok(FileNamer { entries })
+    }
+}
+
+impl Namer for FileNamer {
+    fn lookup(&self, path: &Path) -> io::Result<NameTree<Bound>> {
+        let entry = self.entries
+            .iter()
+            .filter(|(id, _)| path.starts_with(id))
+            .max_by_key(|(id, _)| id.len());
+        Ok(match entry {
+               Some((_, addrs)) if addrs.is_empty() => NameTree::Empty,
+               Some((id, addrs)) => {
+                   NameTree::Leaf(Bound {
+                                      id: id.clone(),
+                                      addrs: addrs.clone(),
+                                      residual: path.drop(id.len()),
+                                  })
+               }
+               None => NameTree::Neg,
+           })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bound, FileNamer, InetNamer, Namer};
+    use crate::naming::name_tree::NameTree;
+    use crate::naming::path::Path;
+
+    fn path(s: &str) -> Path {
+        Path::read(s).unwrap()
+    }
+
+    #[test]
+    fn test_inet() {
+        assert_eq!(InetNamer.lookup(&path("/127.0.0.1/8080/users")).unwrap(),
+                   NameTree::Leaf(Bound {
+                                      id: path("/127.0.0.1/8080"),
+                                      addrs: vec!["127.0.0.1:8080".parse().unwrap()],
+                                      residual: path("/users"),
+                                  }));
+        assert_eq!(InetNamer.lookup(&path("/127.0.0.1")).unwrap(), NameTree::Neg);
+        assert_eq!(InetNamer.lookup(&path("/127.0.0.1/http")).unwrap(), NameTree::Neg);
+        assert_eq!(InetNamer.lookup(&path("/localhost/8080")).unwrap(), NameTree::Neg);
+        assert_eq!(InetNamer.lookup(&path("/::1/8080")).unwrap().to_string(), "/::1/8080");
+    }
+
+    #[test]
+    fn test_file() {
+        let namer = FileNamer::read("# Services.\n\
+                                     /users 10.0.0.1:8080 10.0.0.2:8080\n\
+                                     \n\
+                                     /users/admin 10.0.0.3:8080 # Staging.\n\
+                                     /#/x 10.0.0.4:8080\n\
+                                     /gone\n")
+            .unwrap();
+        let bound = |s: &str| namer.lookup(&path(s)).unwrap();
+        match bound("/users/list") {
+            NameTree::Leaf(bound) => {
+                assert_eq!(bound.id, path("/users"));
+                assert_eq!(bound.addrs.len(), 2);
+                assert_eq!(bound.residual, path("/list"));
+            }
+            tree => panic!("unexpected tree {}", tree),
+        }
+        assert_eq!(bound("/users/admin").to_string(), "/users/admin");
+        assert_eq!(bound("/#/x").to_string(), "/#/x");
+        assert_eq!(bound("/gone"), NameTree::Empty);
+        assert_eq!(bound("/other"), NameTree::Neg);
+
+        let err = FileNamer::read("/a 10.0.0.1:80\n/b 10.0.0.1\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid socket address syntax");
+        let err = FileNamer::read("b 10.0.0.1:80").unwrap_err();
+        assert!(err.to_string().starts_with("line 1: expected"), "{}", err);
+    }
+}
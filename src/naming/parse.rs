@@ -0,0 +1,312 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::naming::dtab::{Dentry, Dtab, Elem, Prefix};
+use crate::naming::name_tree::{NameTree, Weighted};
+use crate::naming::path::{is_showable, Path};
+
+/**
+ * What went wrong reading a path, name tree, dentry or dtab.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /** Something else was expected at the offset, e.g. `'=>'`. */
+    Expected(&'static str),
+    /** A `\` not starting a `\xHH` escape. */
+    BadEscape,
+    /** The escaped bytes of a path element are not valid UTF-8. */
+    BadUtf8,
+    /** A union weight which is not a number. */
+    BadWeight,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseErrorKind::Expected(what) => write!(f, "expected {}", what),
+            ParseErrorKind::BadEscape => write!(f, "invalid escape"),
+            ParseErrorKind::BadUtf8 => write!(f, "invalid UTF-8 in path element"),
+            ParseErrorKind::BadWeight => write!(f, "invalid weight"),
+        }
+    }
+}
+
+/**
+ * A syntax error, reported with the offending input marked, e.g.
+ * `expected '=>' at offset 7: '/s/foo [/]b'`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    input: String,
+    offset: usize,
+    kind: ParseErrorKind,
+}
+
+impl ParseError {
+    /** Returns the byte offset of the error in the input. */
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn kind(&self) -> &ParseErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (before, rest) = self.input.split_at(self.offset);
+        let at = rest.chars().next().map_or(0, char::len_utf8);
+        write!(f,
+               "{} at offset {}: '{}[{}]{}'",
+               self.kind,
+               self.offset,
+               before,
+               &rest[..at],
+               &rest[at..])
+    }
+}
+
+impl Error for ParseError {}
+
+/**
+ * A recursive descent parser for Finagle's syntax:
+ *
+ * ```text
+ * dtab     ::= [dentry (';' dentry)* [';']]
+ * dentry   ::= prefix '=>' tree
+ * tree     ::= union ('|' union)*
+ * union    ::= weighted ('&' weighted)*
+ * weighted ::= [number '*'] simple
+ * simple   ::= path | '!' | '~' | '$' | '(' tree ')'
+ * ```
+ *
+ * Whitespace is allowed between tokens.
+ */
+pub struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /** Parses all of `input` with `f`. */
+    pub fn parse<T, F>(input: &'a str, f: F) -> Result<T, ParseError>
+        where F: FnOnce(&mut Parser<'a>) -> Result<T, ParseError>
+    {
+        let mut parser = Parser { input, pos: 0 };
+        let res = f(&mut parser)?;
+        parser.skip_whitespace();
+        if parser.pos < input.len() {
+            return Err(parser.error(ParseErrorKind::Expected("end of input")));
+        }
+        Ok(res)
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            input: self.input.to_string(),
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /** Skips whitespace and consumes `token` if it comes next. */
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        let found = self.input[self.pos..].starts_with(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    fn expect(&mut self, token: &str, what: &'static str) -> Result<(), ParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(ParseErrorKind::Expected(what)))
+        }
+    }
+
+    pub fn path(&mut self) -> Result<Path, ParseError> {
+        self.expect("/", "'/'")?;
+        let mut elems = Vec::new();
+        if self.peek().is_some_and(|c| is_showable(c) || c == b'\\') {
+            loop {
+                elems.push(self.label()?);
+                // Unlike `eat`, no whitespace is allowed within a path.
+                if self.peek() != Some(b'/') {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        Ok(Path::new(elems))
+    }
+
+    fn label(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        let mut bytes = Vec::new();
+        while let Some(c) = self.peek() {
+            if is_showable(c) {
+                bytes.push(c);
+                self.pos += 1;
+            } else if c == b'\\' {
+                bytes.push(self.escape()?);
+            } else {
+                break;
+            }
+        }
+        if bytes.is_empty() {
+            return Err(self.error(ParseErrorKind::Expected("a path element")));
+        }
+        String::from_utf8(bytes).map_err(|_| {
+            ParseError {
+                input: self.input.to_string(),
+                offset: start,
+                kind: ParseErrorKind::BadUtf8,
+            }
+        })
+    }
+
+    fn escape(&mut self) -> Result<u8, ParseError> {
+        let escape = self.input.get(self.pos..self.pos + 4);
+        let byte = escape.filter(|e| e.starts_with("\\x"))
+            .and_then(|e| u8::from_str_radix(&e[2..], 16).ok());
+        match byte {
+            Some(byte) => {
+                self.pos += 4;
+                Ok(byte)
+            }
+            None => Err(self.error(ParseErrorKind::BadEscape)),
+        }
+    }
+
+    pub fn prefix(&mut self) -> Result<Prefix, ParseError> {
+        self.expect("/", "'/'")?;
+        let mut elems = Vec::new();
+        if self.peek().is_some_and(|c| is_showable(c) || c == b'\\' || c == b'*') {
+            loop {
+                if self.peek() == Some(b'*') {
+                    self.pos += 1;
+                    elems.push(Elem::Any);
+                } else {
+                    elems.push(Elem::Label(self.label()?));
+                }
+                if self.peek() != Some(b'/') {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        Ok(Prefix::new(elems))
+    }
+
+    pub fn tree(&mut self) -> Result<NameTree<Path>, ParseError> {
+        let mut trees = vec![self.union()?];
+        while self.eat("|") {
+            trees.push(self.union()?);
+        }
+        Ok(if trees.len() == 1 {
+            trees.pop().unwrap()
+        } else {
+            NameTree::Alt(trees)
+        })
+    }
+
+    fn union(&mut self) -> Result<NameTree<Path>, ParseError> {
+        let first = self.weighted()?;
+        let mut more = self.eat("&");
+        // A single tree without a weight is no union.
+        if first.0.is_none() && !more {
+            return Ok(first.1);
+        }
+        let mut trees = vec![first];
+        while more {
+            trees.push(self.weighted()?);
+            more = self.eat("&");
+        }
+        let trees = trees.into_iter()
+            .map(|(weight, tree)| {
+                Weighted::new(weight.unwrap_or(Weighted::<Path>::DEFAULT_WEIGHT), tree)
+            })
+            .collect();
+        Ok(NameTree::Union(trees))
+    }
+
+    fn weighted(&mut self) -> Result<(Option<f64>, NameTree<Path>), ParseError> {
+        self.skip_whitespace();
+        if !self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            return Ok((None, self.simple()?));
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == b'.') {
+            self.pos += 1;
+        }
+        let weight = match self.input[start..self.pos].parse() {
+            Ok(weight) => weight,
+            Err(_) => {
+                self.pos = start;
+                return Err(self.error(ParseErrorKind::BadWeight));
+            }
+        };
+        self.expect("*", "'*'")?;
+        Ok((Some(weight), self.simple()?))
+    }
+
+    fn simple(&mut self) -> Result<NameTree<Path>, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let tree = self.tree()?;
+                self.expect(")", "')'")?;
+                Ok(tree)
+            }
+            Some(b'!') => {
+                self.pos += 1;
+                Ok(NameTree::Fail)
+            }
+            Some(b'~') => {
+                self.pos += 1;
+                Ok(NameTree::Neg)
+            }
+            Some(b'$') => {
+                self.pos += 1;
+                Ok(NameTree::Empty)
+            }
+            Some(b'/') => self.path().map(NameTree::Leaf),
+            _ => Err(self.error(ParseErrorKind::Expected("a path or one of '!', '~', '$', '('"))),
+        }
+    }
+
+    pub fn dentry(&mut self) -> Result<Dentry, ParseError> {
+        let prefix = self.prefix()?;
+        self.expect("=>", "'=>'")?;
+        let dst = self.tree()?;
+        Ok(Dentry::new(prefix, dst))
+    }
+
+    pub fn dtab(&mut self) -> Result<Dtab, ParseError> {
+        let mut dentries = Vec::new();
+        self.skip_whitespace();
+        while self.pos < self.input.len() {
+            dentries.push(self.dentry()?);
+            if !self.eat(";") {
+                break;
+            }
+            self.skip_whitespace();
+        }
+        Ok(Dtab::new(dentries))
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::naming::parse::{ParseError, Parser};
+
+/**
+ * A hierarchical name, such as `/s/foo`, made up of non-empty elements. The
+ * empty path is written `/`.
+ *
+ * Elements are written as is if they only contain the characters
+ * `[0-9A-Za-z_:.#$%-]`; other bytes are escaped as `\xHH`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Path {
+    elems: Vec<String>,
+}
+
+impl Path {
+    /** Creates a path of the given elements, which must not be empty. */
+    pub fn new(elems: Vec<String>) -> Path {
+        assert!(elems.iter().all(|e| !e.is_empty()), "empty path element");
+        Path { elems }
+    }
+
+    pub fn empty() -> Path {
+        Path::default()
+    }
+
+    /** Parses a path such as `/s/foo`. */
+    pub fn read(s: &str) -> Result<Path, ParseError> {
+        Parser::parse(s, Parser::path)
+    }
+
+    pub fn elems(&self) -> &[String] {
+        &self.elems
+    }
+
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        self.elems.starts_with(&prefix.elems)
+    }
+
+    /** Returns the path without its first `n` elements. */
+    pub fn drop(&self, n: usize) -> Path {
+        Path { elems: self.elems[n.min(self.len())..].to_vec() }
+    }
+
+    /** Returns the path of this path's elements followed by `suffix`'s. */
+    pub fn concat(&self, suffix: &Path) -> Path {
+        let mut elems = self.elems.clone();
+        elems.extend_from_slice(&suffix.elems);
+        Path { elems }
+    }
+}
+
+/** Whether `c` can be written unescaped in a path element. */
+pub fn is_showable(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"_:.#$%-".contains(&c)
+}
+
+/** Writes a path element, escaping the bytes which are not showable. */
+pub fn show_elem(f: &mut fmt::Formatter, elem: &str) -> fmt::Result {
+    for &c in elem.as_bytes() {
+        if is_showable(c) {
+            write!(f, "{}", c as char)?;
+        } else {
+            write!(f, "\\x{:02x}", c)?;
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.elems.is_empty() {
+            return write!(f, "/");
+        }
+        for elem in &self.elems {
+            write!(f, "/")?;
+            show_elem(f, elem)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Path {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Path, ParseError> {
+        Path::read(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Path;
+
+    #[test]
+    fn test_read() {
+        let path = Path::read("/s/foo-bar/$/127.0.0.1:8080").unwrap();
+        assert_eq!(path.elems(), ["s", "foo-bar", "$", "127.0.0.1:8080"]);
+        assert!(Path::read("/").unwrap().is_empty());
+        assert_eq!(Path::read(" /a ").unwrap(), Path::new(vec!["a".to_string()]));
+
+        for bad in ["", "a", "//", "/a/", "/a b", "/a\\x4", "/\\xff"] {
+            assert!(Path::read(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(Path::read("/a/").unwrap_err().to_string(),
+                   "expected a path element at offset 3: '/a/[]'");
+        assert_eq!(Path::read("/a b").unwrap_err().to_string(),
+                   "expected end of input at offset 3: '/a [b]'");
+    }
+
+    #[test]
+    fn test_show() {
+        let path = Path::new(vec!["s".to_string(), "a b/ü".to_string()]);
+        assert_eq!(path.to_string(), "/s/a\\x20b\\x2f\\xc3\\xbc");
+        assert_eq!(Path::read(&path.to_string()).unwrap(), path);
+        assert_eq!(Path::empty().to_string(), "/");
+    }
+
+    #[test]
+    fn test_ops() {
+        let path = Path::read("/s/foo/bar").unwrap();
+        assert!(path.starts_with(&Path::read("/s/foo").unwrap()));
+        assert!(!path.starts_with(&Path::read("/s/fo").unwrap()));
+        assert_eq!(path.drop(2), Path::read("/bar").unwrap());
+        assert_eq!(Path::read("/a").unwrap().concat(&path.drop(1)),
+                   Path::read("/a/foo/bar").unwrap());
+    }
+}
@@ -0,0 +1,267 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::naming::dtab::Dtab;
+use crate::naming::name_tree::{NameTree, Weighted};
+use crate::naming::namer::{Bound, InetNamer, Namer};
+use crate::naming::path::Path;
+use crate::rpc::Request;
+
+/**
+ * The maximum number of times a path is rewritten by the dtab while it is
+ * bound, beyond which it is assumed to loop.
+ */
+pub const MAX_DEPTH: usize = 100;
+
+/**
+ * The maximum number of lookups made to bind a path, across all of its
+ * alternatives and union members. Bounds the work of dtabs in which each
+ * rewrite multiplies the paths to bind, such as `/a=>/b & /b`.
+ */
+pub const MAX_STEPS: usize = 1000;
+
+/**
+ * The reasons a path can fail to be bound.
+ */
+#[derive(Debug)]
+pub enum ResolveError {
+    /**
+     * The path was rewritten more than `MAX_DEPTH` times, or binding took
+     * more than `MAX_STEPS` lookups.
+     */
+    TooDeep(Path),
+    /** The namer of the path failed to look it up. */
+    Lookup(Path, io::Error),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::TooDeep(ref path) => {
+                write!(f, "delegation of {} exceeded its limits", path)
+            }
+            ResolveError::Lookup(ref path, ref e) => write!(f, "lookup of {} failed: {}", path, e),
+        }
+    }
+}
+
+impl error::Error for ResolveError {}
+
+/**
+ * Binds paths to addresses by delegation: a path is rewritten by a dtab
+ * until it reaches the prefix of a namer, which binds it.
+ *
+ * Alternatives are bound in order until one is not negative, so that e.g.
+ * `/s => /a | /b` falls back to `/b` if no dentry matches `/a`. A failure
+ * stops resolution, and negative members of unions are dropped.
+ *
+ * The `/$/inet` namer is registered from the start.
+ */
+pub struct Resolver {
+    dtab: Dtab,
+    namers: Vec<(Path, Box<dyn Namer>)>,
+}
+
+impl Resolver {
+    /** Creates a resolver rewriting paths by `dtab`. */
+    pub fn new(dtab: Dtab) -> Resolver {
+        let inet = Path::new(vec!["$".to_string(), "inet".to_string()]);
+        Resolver { dtab, namers: vec![(inet, Box::new(InetNamer))] }
+    }
+
+    /**
+     * Binds the paths starting with `prefix` with `namer`, e.g. at
+     * `/#/io.l5d.fs`, instead of rewriting them.
+     */
+    pub fn with_namer<N>(mut self, prefix: Path, namer: N) -> Self
+        where N: Namer + 'static
+    {
+        self.namers.push((prefix, Box::new(namer)));
+        self
+    }
+
+    pub fn dtab(&self) -> &Dtab {
+        &self.dtab
+    }
+
+    /**
+     * Binds `path` by the resolver's dtab followed by `local`, whose dentries
+     * take precedence.
+     */
+    pub fn bind(&self, local: &Dtab, path: &Path) -> Result<NameTree<Bound>, ResolveError> {
+        let dtab = if local.is_empty() { self.dtab.clone() } else { self.dtab.concat(local) };
+        let mut steps = MAX_STEPS;
+        self.bind_path(&dtab, path, 0, &mut steps)
+    }
+
+    /** Binds the destination of `req` with the dtab it carries. */
+    pub fn route(&self, req: &Request) -> Result<NameTree<Bound>, ResolveError> {
+        self.bind(&req.dtab, &req.dst)
+    }
+
+    fn bind_path(&self, dtab: &Dtab, path: &Path, depth: usize, steps: &mut usize)
+                 -> Result<NameTree<Bound>, ResolveError> {
+        if *steps == 0 {
+            return Err(ResolveError::TooDeep(path.clone()));
+        }
+        *steps -= 1;
+        let namer = self.namers
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len());
+        if let Some((prefix, namer)) = namer {
+            let tree = namer.lookup(&path.drop(prefix.len()))
+                .map_err(|e| ResolveError::Lookup(path.clone(), e))?;
+            return Ok(tree.map(&mut |bound: Bound| {
+                                    Bound { id: prefix.concat(&bound.id), ..bound }
+                                }));
+        }
+        if depth == MAX_DEPTH {
+            return Err(ResolveError::TooDeep(path.clone()));
+        }
+        self.bind_tree(dtab, &dtab.lookup(path), depth + 1, steps)
+    }
+
+    fn bind_tree(&self, dtab: &Dtab, tree: &NameTree<Path>, depth: usize, steps: &mut usize)
+                 -> Result<NameTree<Bound>, ResolveError> {
+        match *tree {
+            NameTree::Leaf(ref path) => self.bind_path(dtab, path, depth, steps),
+            NameTree::Alt(ref trees) => {
+                for tree in trees {
+                    match self.bind_tree(dtab, tree, depth, steps)? {
+                        NameTree::Neg => continue,
+                        bound => return Ok(bound),
+                    }
+                }
+                Ok(NameTree::Neg)
+            }
+            NameTree::Union(ref trees) => {
+                let mut bound = Vec::with_capacity(trees.len());
+                for weighted in trees {
+                    match self.bind_tree(dtab, &weighted.tree, depth, steps)? {
+                        NameTree::Neg => {}
+                        tree => bound.push(Weighted::new(weighted.weight(), tree)),
+                    }
+                }
+                Ok(match bound.len() {
+                       0 => NameTree::Neg,
+                       1 => bound.pop().unwrap().tree,
+                       _ => NameTree::Union(bound),
+                   })
+            }
+            NameTree::Neg => Ok(NameTree::Neg),
+            NameTree::Fail => Ok(NameTree::Fail),
+            NameTree::Empty => Ok(NameTree::Empty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{ResolveError, Resolver};
+    use crate::naming::dtab::Dtab;
+    use crate::naming::name_tree::NameTree;
+    use crate::naming::namer::{Bound, FileNamer, Namer};
+    use crate::naming::path::Path;
+    use crate::rpc::Request;
+
+    fn fs_resolver(dtab: &str) -> Resolver {
+        let namer = FileNamer::read("/users 10.0.0.1:8080\n/staging 10.0.1.1:8080").unwrap();
+        Resolver::new(dtab.parse().unwrap()).with_namer("/#/fs".parse().unwrap(), namer)
+    }
+
+    fn bind(resolver: &Resolver, path: &str) -> String {
+        resolver.bind(&Dtab::empty(), &path.parse().unwrap()).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_bind() {
+        let resolver = fs_resolver("/fs=>/#/fs;\
+                                    /s=>/fs;\
+                                    /s/users=>/$/inet/127.0.0.1/9990|/s/users-old;\
+                                    /s/users-old=>/$/inet/127.0.0.1/9991");
+        assert_eq!(bind(&resolver, "/$/inet/127.0.0.1/8080"), "/$/inet/127.0.0.1/8080");
+        assert_eq!(bind(&resolver, "/s/users"), "/$/inet/127.0.0.1/9990");
+        assert_eq!(bind(&resolver, "/s/staging"), "/#/fs/staging");
+        assert_eq!(bind(&resolver, "/s/other"), "~");
+        assert_eq!(bind(&resolver, "/t/users"), "~");
+
+        match resolver.bind(&Dtab::empty(), &"/s/users/list".parse().unwrap()).unwrap() {
+            NameTree::Leaf(Bound { addrs, residual, .. }) => {
+                assert_eq!(addrs, ["127.0.0.1:9990".parse().unwrap()]);
+                assert_eq!(residual.to_string(), "/list");
+            }
+            tree => panic!("unexpected tree {}", tree),
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        // The later dentry is tried first, and falls back on the earlier one.
+        let resolver = fs_resolver("/s=>/#/fs;/s/users=>/s/missing|!;/s/admin=>/nowhere");
+        assert_eq!(bind(&resolver, "/s/users"), "!");
+        assert_eq!(bind(&resolver, "/s/admin"), "~");
+        let resolver = fs_resolver("/s=>/#/fs;/s=>/nowhere;/s/users=>/s/nowhere|~");
+        assert_eq!(bind(&resolver, "/s/users"), "/#/fs/users");
+        assert_eq!(bind(&resolver, "/s/staging"), "/#/fs/staging");
+    }
+
+    #[test]
+    fn test_union() {
+        let resolver = fs_resolver("/s=>/#/fs;/u=>0.8*/s/users & 0.2*/s/staging & /s/none");
+        assert_eq!(bind(&resolver, "/u"), "0.8*/#/fs/users & 0.2*/#/fs/staging");
+        let resolver = fs_resolver("/s=>/#/fs;/u=>0.8*/s/users & 0.2*/s/none");
+        assert_eq!(bind(&resolver, "/u"), "/#/fs/users");
+    }
+
+    #[test]
+    fn test_local() {
+        let resolver = fs_resolver("/s=>/#/fs");
+        let mut req = Request::new("/s/users".parse().unwrap(), vec![]);
+        assert_eq!(resolver.route(&req).unwrap().to_string(), "/#/fs/users");
+        req.dtab = "/s/users=>/s/staging".parse().unwrap();
+        assert_eq!(resolver.route(&req).unwrap().to_string(), "/#/fs/staging");
+        assert_eq!(resolver.dtab().len(), 1);
+    }
+
+    #[test]
+    fn test_errors() {
+        struct Broken;
+
+        impl Namer for Broken {
+            fn lookup(&self, _: &Path) -> io::Result<NameTree<Bound>> {
+                Err(io::Error::other("broken"))
+            }
+        }
+
+        let resolver = fs_resolver("/a=>/b;/b=>/a;/c=>/#/broken")
+            .with_namer("/#/broken".parse().unwrap(), Broken);
+        match resolver.bind(&Dtab::empty(), &"/a".parse().unwrap()) {
+            Err(ResolveError::TooDeep(path)) => assert_eq!(path.to_string(), "/a"),
+            res => panic!("unexpected result {:?}", res),
+        }
+        match resolver.bind(&Dtab::empty(), &"/c/x".parse().unwrap()) {
+            Err(err @ ResolveError::Lookup(..)) => {
+                assert_eq!(err.to_string(), "lookup of /#/broken/x failed: broken")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_doubling() {
+        // Every rewrite doubles the paths to bind, 2^64 in total.
+        let dtab = (0..64)
+            .map(|i| format!("/a{}=>/a{} & /a{}", i, i + 1, i + 1))
+            .collect::<Vec<_>>()
+            .join(";");
+        let resolver = fs_resolver(&dtab);
+        match resolver.bind(&Dtab::empty(), &"/a0".parse().unwrap()) {
+            Err(ResolveError::TooDeep(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
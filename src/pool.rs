@@ -0,0 +1,222 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::client::Client;
+use crate::failure_detector::Status;
+use crate::retry::RetryPolicy;
+use crate::rpc::{Error, Request, Response};
+
+/**
+ * Balances calls over the sessions of several `Client`s, round-robin.
+ *
+ * Sessions which are draining or closed are dropped from the pool, and
+ * suspect ones are only used when no session is open. Busy sessions, whose
+ * lease expired, are skipped until the server renews the lease. A call which
+ * could not be sent because its session started draining is transparently
+ * moved to another session, up to once per session in the pool, and so is a
+ * nacked request, as far as the retry policy allows: a nack guarantees the
+ * request was not processed.
+ */
+pub struct Pool {
+    clients: Mutex<Vec<Client>>,
+    next: AtomicUsize,
+    retries: RetryPolicy,
+}
+
+impl Pool {
+    pub fn new(clients: Vec<Client>) -> Pool {
+        Pool {
+            clients: Mutex::new(clients),
+            next: AtomicUsize::new(0),
+            retries: RetryPolicy::default(),
+        }
+    }
+
+    /** Retries nacked requests as `policy` allows, rather than the default policy. */
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Pool {
+        self.retries = policy;
+        self
+    }
+
+    /** Adds the client of a new session, e.g. one replacing a drained session. */
+    pub fn push(&self, client: Client) {
+        self.clients.lock().unwrap().push(client);
+    }
+
+    /** Returns the number of sessions which still take requests. */
+    pub fn len(&self) -> usize {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(usable);
+        clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /**
+     * Dispatches `req` on one of the sessions, failing with `Error::Closed`
+     * if none is left, or with `Error::Nack` if they are all busy.
+     */
+    pub async fn call(&self, req: Request) -> Result<Response, Error> {
+        self.retries.request(Instant::now());
+        let mut retries = 0;
+        // Every session drains at most once, so a request is moved at most
+        // once per session.
+        let mut moves = self.clients.lock().unwrap().len();
+        let mut last = None;
+        loop {
+            let client = self.pick(last.as_ref())?;
+            match client.call(req.clone()).await {
+                // The request never left; the session is dropped on the next pick.
+                Err(Error::Draining) if moves > 0 => {
+                    moves -= 1;
+                    debug!("session draining; retrying {}", req.dst);
+                }
+                Err(ref e) if self.retries.should_retry(e, retries, Instant::now()) => {
+                    retries += 1;
+                    debug!("request nacked; retrying {}, retries={}", req.dst, retries);
+                }
+                res => return res,
+            }
+            last = Some(client);
+        }
+    }
+
+    /** Picks a session, preferring one other than the session of `last`. */
+    fn pick(&self, last: Option<&Client>) -> Result<Client, Error> {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(usable);
+        if clients.is_empty() {
+            return Err(Error::Closed);
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = clients.len();
+        let candidates = (0..n).map(|i| &clients[(start + i) % n]);
+        let fresh = |client: &Client| !last.is_some_and(|last| last.same_session(client));
+        let preferences = [(Status::Open, true),
+                           (Status::Suspect, true),
+                           (Status::Open, false),
+                           (Status::Suspect, false)];
+        preferences.iter()
+            .find_map(|&(status, fresh_only)| {
+                candidates.clone()
+                    .find(|client| client.status() == status && (!fresh_only || fresh(client)))
+            })
+            .cloned()
+            .ok_or(Error::Nack)
+    }
+}
+
+fn usable(client: &Client) -> bool {
+    match client.status() {
+        Status::Open | Status::Suspect | Status::Busy => true,
+        Status::Draining | Status::Closed => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::DuplexStream;
+    use tokio_util::codec::Framed;
+
+    use super::Pool;
+    use crate::client::Client;
+    use crate::retry::{self, RetryBudget, RetryPolicy};
+    use crate::rpc::{Error, Request};
+    use crate::transport::message::Message;
+    use crate::transport::mux_framer::MuxCodec;
+
+    #[tokio::test]
+    async fn test_skips_draining() {
+        let (a, server_a) = tokio::io::duplex(1024);
+        let (b, server_b) = tokio::io::duplex(1024);
+        let (client_a, dispatcher_a) = Client::new(Framed::new(a, MuxCodec::new()));
+        let (client_b, dispatcher_b) = Client::new(Framed::new(b, MuxCodec::new()));
+        tokio::spawn(dispatcher_a.run());
+        tokio::spawn(dispatcher_b.run());
+        let pool = Pool::new(vec![client_a, client_b]);
+
+        let mut server_a = Framed::new(server_a, MuxCodec::new());
+        server_a.send(Message::Tdrain { tag: 1 }).await.unwrap();
+        match server_a.next().await {
+            Some(Ok(Ok(Message::Rdrain { tag: 1 }))) => {}
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        let server_b = tokio::spawn(async move {
+            let mut server_b = Framed::new(server_b, MuxCodec::new());
+            for _ in 0..2 {
+                match server_b.next().await {
+                    Some(Ok(Ok(Message::Tdispatch { tag, req, .. }))) => {
+                        server_b.send(Message::rdispatch_ok(tag, req)).await.unwrap()
+                    }
+                    frame => panic!("unexpected frame {:?}", frame),
+                }
+            }
+            server_b
+        });
+        for i in 0..2 {
+            let rep = pool.call(Request::new("/a".parse().unwrap(), vec![i])).await.unwrap();
+            assert_eq!(rep.body, vec![i]);
+        }
+        let _server_b = server_b.await.unwrap();
+        assert_eq!(pool.len(), 1);
+
+        drop(server_a);
+        let pool = Pool::new(vec![]);
+        assert!(matches!(pool.call(Request::new("/a".parse().unwrap(), vec![])).await,
+                         Err(Error::Closed)));
+    }
+
+    /** Serves a session, nacking every request or echoing its body. */
+    async fn server(io: DuplexStream, nack: bool) {
+        let mut framed = Framed::new(io, MuxCodec::new());
+        while let Some(Ok(Ok(msg))) = framed.next().await {
+            let reply = match msg {
+                Message::Tdispatch { tag, .. } if nack => {
+                    Message::RdispatchNack {
+                        tag,
+                        contexts: vec![],
+                    }
+                }
+                Message::Tdispatch { tag, req, .. } => Message::rdispatch_ok(tag, req),
+                msg => panic!("unexpected message {:?}", msg),
+            };
+            framed.send(reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_nacks() {
+        let mut clients = vec![];
+        for nack in [true, false] {
+            let (ours, theirs) = tokio::io::duplex(1024);
+            let (client, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+            tokio::spawn(dispatcher.run());
+            tokio::spawn(server(theirs, nack));
+            clients.push(client);
+        }
+
+        // A new pool sends its first call to the nacking session, then to the other one.
+        for i in 0..2 {
+            let pool = Pool::new(clients.clone());
+            let rep = pool.call(Request::new("/a".parse().unwrap(), vec![i])).await.unwrap();
+            assert_eq!(rep.body, vec![i]);
+        }
+
+        // Without a budget, the nack is returned.
+        let budget = retry::Config {
+            min_retries_per_sec: 0.0,
+            ..retry::Config::default()
+        };
+        let policy = RetryPolicy::new(3, RetryBudget::new(budget, Instant::now()));
+        let pool = Pool::new(clients).with_retry_policy(policy);
+        assert!(matches!(pool.call(Request::new("/a".parse().unwrap(), vec![])).await,
+                         Err(Error::Nack)));
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::rpc::Error;
+
+/**
+ * The settings of a `RetryBudget`.
+ */
+#[derive(Debug, Clone)]
+pub struct Config {
+    /** The fraction of a retry each request deposits into the budget. */
+    pub percent_can_retry: f64,
+    /** The retries granted every second regardless of the request rate. */
+    pub min_retries_per_sec: f64,
+    /** The most retries the budget saves up. */
+    pub max_balance: f64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            percent_can_retry: 0.2,
+            min_retries_per_sec: 10.0,
+            max_balance: 100.0,
+        }
+    }
+}
+
+/**
+ * A token bucket bounding the retries to a fraction of the requests, in the
+ * manner of Finagle's `RetryBudget`: every request deposits a fraction of a
+ * retry, the budget is topped up at a minimum rate, and every retry
+ * withdraws a whole one. This keeps retries from piling onto servers which
+ * are overloaded already.
+ */
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: Config,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    balance: f64,
+    updated: Instant,
+}
+
+impl RetryBudget {
+    /** Creates a budget holding a second's worth of minimum retries at `now`. */
+    pub fn new(config: Config, now: Instant) -> RetryBudget {
+        let balance = config.min_retries_per_sec.min(config.max_balance);
+        RetryBudget {
+            config,
+            bucket: Mutex::new(Bucket {
+                balance,
+                updated: now,
+            }),
+        }
+    }
+
+    /** Deposits the share of a request made at `now`. */
+    pub fn deposit(&self, now: Instant) {
+        self.update(now, |balance| *balance += self.config.percent_can_retry);
+    }
+
+    /** Withdraws a retry at `now`, returning whether the budget allowed it. */
+    pub fn try_withdraw(&self, now: Instant) -> bool {
+        self.update(now, |balance| {
+            let allowed = *balance >= 1.0;
+            if allowed {
+                *balance -= 1.0;
+            }
+            allowed
+        })
+    }
+
+    /** Returns the retries available at `now`. */
+    pub fn balance(&self, now: Instant) -> f64 {
+        self.update(now, |balance| *balance)
+    }
+
+    fn update<R, F: FnOnce(&mut f64) -> R>(&self, now: Instant, f: F) -> R {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.updated = bucket.updated.max(now);
+        let refill = elapsed.as_secs_f64() * self.config.min_retries_per_sec;
+        bucket.balance = (bucket.balance + refill).min(self.config.max_balance);
+        let res = f(&mut bucket.balance);
+        bucket.balance = bucket.balance.min(self.config.max_balance);
+        res
+    }
+}
+
+/**
+ * Decides which failed requests to retry: those which the server did not
+ * process, as told by `Error::is_retryable`, up to `max_retries` times per
+ * request and as far as the budget allows.
+ */
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    budget: RetryBudget,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, budget: RetryBudget) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            budget,
+        }
+    }
+
+    /** Records a request made at `now`, which earns retries for the budget. */
+    pub fn request(&self, now: Instant) {
+        self.budget.deposit(now);
+    }
+
+    /**
+     * Returns whether to retry a request which failed with `err` after
+     * `retries` retries, withdrawing the retry from the budget if so.
+     */
+    pub fn should_retry(&self, err: &Error, retries: u32, now: Instant) -> bool {
+        err.is_retryable() && retries < self.max_retries && self.budget.try_withdraw(now)
+    }
+
+    pub fn budget(&self) -> &RetryBudget {
+        &self.budget
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(3, RetryBudget::new(Config::default(), Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Config, RetryBudget, RetryPolicy};
+    use crate::rpc::Error;
+
+    #[test]
+    fn test_budget() {
+        let start = Instant::now();
+        let config = Config {
+            percent_can_retry: 0.5,
+            min_retries_per_sec: 1.0,
+            max_balance: 2.0,
+        };
+        let budget = RetryBudget::new(config, start);
+        assert!(budget.try_withdraw(start));
+        assert!(!budget.try_withdraw(start));
+
+        // Requests earn retries.
+        budget.deposit(start);
+        budget.deposit(start);
+        assert!(budget.try_withdraw(start));
+        assert!(!budget.try_withdraw(start));
+
+        // So does time, up to the maximum balance.
+        assert_eq!(budget.balance(start + Duration::from_millis(500)), 0.5);
+        assert_eq!(budget.balance(start + Duration::from_secs(10)), 2.0);
+    }
+
+    #[test]
+    fn test_policy() {
+        let now = Instant::now();
+        let policy = RetryPolicy::new(2, RetryBudget::new(Config::default(), now));
+        assert!(policy.should_retry(&Error::Nack, 0, now));
+        assert!(policy.should_retry(&Error::Draining, 1, now));
+        assert!(!policy.should_retry(&Error::Nack, 2, now));
+        assert!(!policy.should_retry(&Error::Closed, 0, now));
+        assert!(!policy.should_retry(&Error::Application("failed".to_string()), 0, now));
+        assert_eq!(policy.budget().balance(now), 8.0);
+    }
+}
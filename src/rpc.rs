@@ -0,0 +1,102 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use crate::{Dtab, Path};
+
+/**
+ * A request dispatched over mux, i.e. the contents of a `Tdispatch`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub dst: Path,
+    pub dtab: Dtab,
+    pub contexts: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /** Creates a request for `dst` without contexts or dtab overrides. */
+    pub fn new(dst: Path, body: Vec<u8>) -> Request {
+        Request {
+            dst,
+            dtab: Dtab::empty(),
+            contexts: Vec::new(),
+            body,
+        }
+    }
+}
+
+/**
+ * A successful reply, i.e. the contents of an `RdispatchOk`.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub contexts: Vec<(Vec<u8>, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(body: Vec<u8>) -> Response {
+        Response {
+            contexts: Vec::new(),
+            body,
+        }
+    }
+}
+
+/**
+ * The reasons a dispatched request can fail.
+ */
+#[derive(Debug)]
+pub enum Error {
+    /** The server failed the request with the given message. */
+    Application(String),
+    /**
+     * The server rejected the request without processing it, e.g. because
+     * it is overloaded; the request is safe to retry elsewhere.
+     */
+    Nack,
+    /** The server could not process the request message at all. */
+    Rerr(String),
+    /** The session is draining, so the request was not sent. */
+    Draining,
+    /** The request was discarded by the client before a reply arrived. */
+    Discarded,
+    /** The session closed before a reply arrived. */
+    Closed,
+    /** The session failed with an I/O error. */
+    Io(io::Error),
+}
+
+impl Error {
+    /**
+     * Returns whether the request is known not to have been processed, so
+     * that it can be retried safely, e.g. on another session.
+     */
+    pub fn is_retryable(&self) -> bool {
+        matches!(*self, Error::Nack | Error::Draining)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Application(ref error) => write!(f, "application error: {}", error),
+            Error::Nack => write!(f, "request rejected by the server"),
+            Error::Rerr(ref error) => write!(f, "server error: {}", error),
+            Error::Draining => write!(f, "session draining"),
+            Error::Discarded => write!(f, "request discarded"),
+            Error::Closed => write!(f, "session closed"),
+            Error::Io(ref e) => write!(f, "session failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
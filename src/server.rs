@@ -0,0 +1,747 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{AbortHandle, Abortable, Aborted};
+use futures::stream::FuturesUnordered;
+use futures::{SinkExt, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::futures::TaskLocalFuture;
+use tokio::time::{self, Instant};
+
+use crate::admission::AdmissionControl;
+use crate::context::Broadcast;
+use crate::lease::Lessor;
+use crate::naming::dtab::Dtab;
+use crate::rpc::{Error, Request, Response};
+use crate::transport::handshake::{HandshakeError, Registry};
+use crate::transport::message::Message;
+use crate::transport::mux_framer::{Frame, Transport};
+use crate::transport::session::{Event, Session};
+
+/**
+ * An asynchronous function from requests to responses, served over mux. A
+ * failed response is sent back as an application error, or as a nack for
+ * `Error::Nack`.
+ *
+ * The service handles a request with the request's dtab as its local dtab
+ * and the request's contexts as its broadcast contexts, which the requests
+ * it makes through a `Client` carry on.
+ */
+pub trait Service {
+    type Future: Future<Output = Result<Response, Error>>;
+
+    fn call(&self, req: Request) -> Self::Future;
+}
+
+impl<F, R> Service for F
+    where F: Fn(Request) -> R,
+          R: Future<Output = Result<Response, Error>>
+{
+    type Future = R;
+
+    fn call(&self, req: Request) -> R {
+        self(req)
+    }
+}
+
+/** A future run with the local dtab and the contexts of its request. */
+type Scoped<F> = TaskLocalFuture<Dtab, TaskLocalFuture<Broadcast, F>>;
+
+/**
+ * A request being served, which resolves to its tag, when it started and its
+ * result, or `Aborted` once discarded.
+ */
+struct InFlight<F> {
+    tag: u32,
+    started: Instant,
+    future: Abortable<Pin<Box<Scoped<F>>>>,
+}
+
+impl<F: Future> Future for InFlight<F> {
+    type Output = (u32, Instant, Result<F::Output, Aborted>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (tag, started) = (self.tag, self.started);
+        Pin::new(&mut self.future).poll(cx).map(|res| (tag, started, res))
+    }
+}
+
+/**
+ * Serves `service` over an established session (i.e. after the handshake).
+ */
+pub fn serve<T, S>(transport: T, service: S) -> Connection<T, S>
+    where T: Transport,
+          S: Service
+{
+    Connection::new(transport, Session::server(), service)
+}
+
+/**
+ * Serves `service` over a session which the client opens with a handshake,
+ * answering its `Tinit` with the features in `registry`. A client which
+ * doesn't handshake is served in legacy mode.
+ */
+pub fn accept<T, S>(transport: T, registry: Registry, service: S) -> Connection<T, S>
+    where T: Transport,
+          S: Service
+{
+    Connection::new(transport, Session::accept(registry), service)
+}
+
+/**
+ * Asks a `Connection` to drain: the client is sent a `Tdrain`, new requests
+ * are nacked, and the session closes once the outstanding requests are
+ * answered, or when the grace period runs out.
+ */
+#[derive(Debug, Clone)]
+pub struct DrainHandle {
+    tx: mpsc::UnboundedSender<Duration>,
+}
+
+impl DrainHandle {
+    /**
+     * Starts draining the session, abandoning the requests which are still
+     * in flight after `grace`. Does nothing if the session is already
+     * draining or closed.
+     */
+    pub fn drain(&self, grace: Duration) {
+        let _ = self.tx.send(grace);
+    }
+}
+
+/**
+ * Drives a server session: every received request is handed to the service
+ * and its response is written back with the request's tag as soon as it is
+ * ready, so requests are served concurrently, up to the limit of the
+ * admission control if there is one. `run` completes when the client closes
+ * the session, or when the session has drained.
+ */
+pub struct Connection<T, S: Service> {
+    transport: T,
+    service: S,
+    session: Session,
+    in_flight: FuturesUnordered<InFlight<S::Future>>,
+    // Used to cancel in-flight requests by tag.
+    handles: HashMap<u32, AbortHandle>,
+    drain_tx: mpsc::UnboundedSender<Duration>,
+    drain_rx: mpsc::UnboundedReceiver<Duration>,
+    // When to give up on the requests in flight, once draining.
+    deadline: Option<Instant>,
+    lessor: Option<Box<dyn Lessor>>,
+    admission: Option<AdmissionControl>,
+    // The negotiated window, applied once the `Rinit` is written.
+    window: Option<Option<usize>>,
+    // A message read off the transport before the connection was built.
+    pending: Option<Message>,
+}
+
+impl<T, S> Connection<T, S>
+    where T: Transport,
+          S: Service
+{
+    fn new(transport: T, session: Session, service: S) -> Connection<T, S> {
+        let (drain_tx, drain_rx) = mpsc::unbounded_channel();
+        Connection {
+            transport,
+            service,
+            session,
+            in_flight: FuturesUnordered::new(),
+            handles: HashMap::new(),
+            drain_tx,
+            drain_rx,
+            deadline: None,
+            lessor: None,
+            admission: None,
+            window: None,
+            pending: None,
+        }
+    }
+
+    /** Returns the number of requests being served. */
+    pub fn in_flight(&self) -> usize {
+        self.session.in_flight()
+    }
+
+    /**
+     * Grants the client leases as decided by `lessor`, so that it stops
+     * routing requests to this session while it is overloaded.
+     */
+    pub fn with_lessor<L: Lessor + 'static>(mut self, lessor: L) -> Self {
+        self.lessor = Some(Box::new(lessor));
+        self
+    }
+
+    /**
+     * Limits the requests served concurrently as `admission` decides,
+     * nacking the requests it rejects.
+     */
+    pub fn with_admission_control(mut self, admission: AdmissionControl) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    /**
+     * Handles `msg` as the first message of the client, before any read from
+     * the transport. This is the first message of a legacy client returned
+     * by `tls::accept`, which was already read off the stream.
+     */
+    pub fn with_pending(mut self, msg: Message) -> Self {
+        self.pending = Some(msg);
+        self
+    }
+
+    /** Returns a handle to drain the session while `run` is serving it. */
+    pub fn drain_handle(&self) -> DrainHandle {
+        DrainHandle { tx: self.drain_tx.clone() }
+    }
+
+    /** Serves requests until the client closes the session or it drained. */
+    pub async fn run(mut self) -> io::Result<()> {
+        if let Some(msg) = self.pending.take() {
+            self.handle(Ok(msg)).await?;
+        }
+        loop {
+            let drained = self.process_events();
+            let renewal = self.check_lease();
+            self.flush().await?;
+            if drained {
+                debug!("session drained");
+                return Ok(());
+            }
+
+            let deadline = self.deadline;
+            tokio::select! {
+                _ = time::sleep_until(renewal.unwrap_or_else(Instant::now)),
+                    if renewal.is_some() => {}
+                Some(grace) = self.drain_rx.recv(), if deadline.is_none() => {
+                    debug!("draining session; in_flight={}", self.in_flight());
+                    self.session.drain();
+                    self.deadline = Some(Instant::now() + grace);
+                }
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => {
+                    warn!("drain deadline passed; abandoned={}", self.in_flight());
+                    return Ok(());
+                }
+                frame = self.transport.next() => match frame {
+                    Some(Ok(frame)) => self.handle(frame).await?,
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        debug!("session closed by the client; abandoned={}", self.in_flight());
+                        return Ok(());
+                    }
+                },
+                Some((tag, started, res)) = self.in_flight.next(),
+                    if !self.in_flight.is_empty() => {
+                    // Discarded requests were already answered with Rdiscarded.
+                    if let Ok(rep) = res {
+                        if let Some(ref mut admission) = self.admission {
+                            admission.finish(tag, started.elapsed());
+                        }
+                        self.handles.remove(&tag);
+                        self.session.respond(tag, rep);
+                    }
+                }
+            }
+        }
+    }
+
+    /**
+     * Hands a frame to the session. A failed handshake is answered with an
+     * `Rerr`, which is written before failing.
+     */
+    async fn handle(&mut self, frame: Frame) -> io::Result<()> {
+        if let Err(e) = self.session.handle(frame) {
+            self.flush().await?;
+            return Err(invalid_data(e));
+        }
+        Ok(())
+    }
+
+    /** Handles the session's events, returning whether it has drained. */
+    fn process_events(&mut self) -> bool {
+        let mut drained = false;
+        while let Some(event) = self.session.poll_event() {
+            match event {
+                Event::Request { tag, req } => {
+                    let admission = match self.admission {
+                        Some(ref mut admission) => admission,
+                        None => {
+                            self.start(tag, req);
+                            continue;
+                        }
+                    };
+                    if admission.offer(tag, req).is_err() {
+                        debug!("nacking request; tag={}, running={}, pending={}",
+                               tag,
+                               admission.running(),
+                               admission.pending());
+                        self.session.respond(tag, Err(Error::Nack));
+                    }
+                }
+                Event::Discarded { tag, .. } => {
+                    // Aborting the future cancels the work on the request.
+                    if let Some(handle) = self.handles.remove(&tag) {
+                        handle.abort();
+                    }
+                    if let Some(ref mut admission) = self.admission {
+                        admission.cancel(tag);
+                    }
+                }
+                Event::Established(negotiated) => {
+                    debug!("session established; window={:?}", negotiated.window);
+                    self.window = Some(negotiated.window.map(|w| w as usize));
+                }
+                Event::Drained => drained = true,
+                event => debug!("ignoring session event {:?}", event),
+            }
+        }
+        while let Some((tag, req)) = self.admission.as_mut().and_then(|a| a.poll_start()) {
+            self.start(tag, req);
+        }
+        drained
+    }
+
+    /** Hands a request to the service. */
+    fn start(&mut self, tag: u32, req: Request) {
+        let (handle, registration) = AbortHandle::new_pair();
+        let dtab = req.dtab.clone();
+        let contexts = Broadcast::from(req.contexts.clone());
+        let future = Dtab::with_local_sync(dtab.clone(), || {
+            contexts.clone().sync_scope(|| self.service.call(req))
+        });
+        let future = Dtab::with_local(dtab, contexts.scope(future));
+        let future = Abortable::new(Box::pin(future), registration);
+        self.in_flight.push(InFlight {
+            tag,
+            started: Instant::now(),
+            future,
+        });
+        self.handles.insert(tag, handle);
+    }
+
+    /**
+     * Grants the lease decided by the lessor, returning when to check back.
+     * A draining session gets no more leases.
+     */
+    fn check_lease(&mut self) -> Option<Instant> {
+        let in_flight = self.session.in_flight();
+        let lessor = match self.lessor {
+            Some(ref mut lessor) if !self.session.is_draining() => lessor,
+            _ => return None,
+        };
+        let now = Instant::now().into_std();
+        if let Some(how_long) = lessor.poll_lease(in_flight, now) {
+            debug!("granting a lease of {:?}; in_flight={}", how_long, in_flight);
+            self.session.lease(how_long);
+        }
+        lessor.next_deadline(now).map(Instant::from_std)
+    }
+
+    /** Writes the messages emitted by the session. */
+    async fn flush(&mut self) -> io::Result<()> {
+        let mut sent = false;
+        while let Some(msg) = self.session.poll_message() {
+            self.transport.feed(msg).await?;
+            sent = true;
+        }
+        if sent {
+            self.transport.flush().await?;
+        }
+        // The client reads the `Rinit` before it knows about the window.
+        if let Some(window) = self.window.take() {
+            self.transport.set_window(window);
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(e: HandshakeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::{BufMut, BytesMut};
+    use futures::future::{self, Either};
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_util::codec::{Decoder, Encoder, Framed};
+
+    use super::{accept, serve};
+    use crate::admission::AdmissionControl;
+    use crate::client::Client;
+    use crate::context::{self, Context};
+    use crate::lease::QueueDepth;
+    use crate::naming::dtab::Dtab;
+    use crate::rpc::{Error, Request, Response};
+    use crate::transport::handshake::{Registry, TINIT_TAG, VERSION};
+    use crate::transport::message::{self, Message};
+    use crate::transport::mux_framer::header::FrameSize;
+    use crate::transport::mux_framer::{MuxCodec, MuxFramed};
+
+    type Reply = Result<Response, Error>;
+
+    fn service(req: Request) -> Either<future::Ready<Reply>, future::Pending<Reply>> {
+        match &req.dst.to_string()[..] {
+            "/slow" => Either::Right(future::pending()),
+            "/nack" => Either::Left(future::err(Error::Nack)),
+            _ => Either::Left(future::ok(Response::new(req.body))),
+        }
+    }
+
+    /** Makes sure the server received every message sent before. */
+    async fn ping(client: &mut Framed<DuplexStream, MuxCodec>) {
+        client.send(Message::Tping { tag: 1 }).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(), Message::Rping { tag: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let conn = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), service).run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+
+        for msg in [Message::tdispatch(2, "/echo".parse().unwrap(), vec![1]),
+                        Message::Treq { tag: 3, req: vec![2] },
+                        Message::Tping { tag: 4 },
+                        Message::tdispatch(5, "/slow".parse().unwrap(), vec![]),
+                        Message::tdispatch(6, "/nack".parse().unwrap(), vec![]),
+                        Message::Tdiscarded {
+                            which: 5,
+                            why: "timeout".to_string(),
+                        }] {
+            client.send(msg).await.unwrap();
+        }
+        // A message of an unknown type.
+        client.get_mut().write_all(&[0, 0, 0, 4, 33, 0, 0, 9]).await.unwrap();
+
+        let mut replies = vec![];
+        for _ in 0..6 {
+            replies.push(client.next().await.unwrap().unwrap().unwrap());
+        }
+        for msg in [Message::rdispatch_ok(2, vec![1]),
+                        Message::RreqOk { tag: 3, reply: vec![2] },
+                        Message::Rping { tag: 4 },
+                        Message::Rdiscarded { tag: 5 },
+                        Message::RdispatchNack {
+                            tag: 6,
+                            contexts: vec![],
+                        }] {
+            assert!(replies.contains(&msg), "missing {:?}", msg);
+        }
+        match replies.iter().find(|m| m.tag() == 9) {
+            Some(&Message::Rerr { .. }) => {}
+            msg => panic!("expected Rerr, got {:?}", msg),
+        }
+
+        drop(client);
+        conn.await.unwrap().unwrap();
+    }
+
+    fn registry(window: u32) -> Registry {
+        let mut registry = Registry::new();
+        registry.register(FrameSize(window));
+        registry
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let conn = tokio::spawn(accept(MuxFramed::new(theirs), registry(16), service).run());
+        let (client, dispatcher) = Client::connect(MuxFramed::new(ours), registry(64));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        let req = Request::new("/echo".parse().unwrap(), vec![7; 100]);
+        assert_eq!(client.call(req).await.unwrap().body, vec![7; 100]);
+
+        drop(client);
+        dispatcher.await.unwrap().unwrap();
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_fragments() {
+        async fn read_frame(io: &mut DuplexStream) -> Vec<u8> {
+            let size = io.read_u32().await.unwrap();
+            let mut frame = vec![0; size as usize];
+            io.read_exact(&mut frame).await.unwrap();
+            frame
+        }
+
+        let (mut ours, theirs) = tokio::io::duplex(1024);
+        let conn = tokio::spawn(accept(Framed::new(theirs, MuxCodec::new()), registry(16), service)
+            .run());
+        let mut codec = MuxCodec::new();
+        let mut wire = BytesMut::new();
+        let tinit = Message::Tinit {
+            tag: TINIT_TAG,
+            version: VERSION,
+            headers: registry(64).offer(),
+        };
+        codec.encode(tinit, &mut wire).unwrap();
+        ours.write_all(&wire).await.unwrap();
+
+        // The Rinit is written whole, before the window applies.
+        let rinit = message::decode(read_frame(&mut ours).await).unwrap();
+        assert_eq!(rinit,
+                   Message::Rinit {
+                       tag: TINIT_TAG,
+                       version: VERSION,
+                       headers: registry(16).offer(),
+                   });
+
+        wire.clear();
+        codec.encode(Message::tdispatch(2, "/echo".parse().unwrap(), vec![7; 40]), &mut wire)
+            .unwrap();
+        ours.write_all(&wire).await.unwrap();
+
+        // The response is fragmented, and reassembles into the whole reply.
+        let mut reply = None;
+        while reply.is_none() {
+            let frame = read_frame(&mut ours).await;
+            assert!(frame.len() <= 4 + 16, "frame of {} bytes", frame.len());
+            wire.clear();
+            wire.put_u32(frame.len() as u32);
+            wire.extend_from_slice(&frame);
+            reply = codec.decode(&mut wire).unwrap();
+        }
+        assert_eq!(reply.unwrap().unwrap(), Message::rdispatch_ok(2, vec![7; 40]));
+
+        drop(ours);
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_bad_tinit() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let conn = tokio::spawn(accept(Framed::new(theirs, MuxCodec::new()), registry(16), service)
+            .run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+        let tinit = Message::Tinit {
+            tag: TINIT_TAG,
+            version: VERSION + 1,
+            headers: vec![],
+        };
+        client.send(tinit).await.unwrap();
+
+        match client.next().await.unwrap().unwrap().unwrap() {
+            Message::Rerr { tag: TINIT_TAG, .. } => {}
+            msg => panic!("expected Rerr, got {:?}", msg),
+        }
+        let err = conn.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_local_dtab() {
+        // A backend answering with the dtab of its requests.
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let backend = |req: Request| future::ok(Response::new(req.dtab.to_string().into_bytes()));
+        let backend = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), backend).run());
+        let (downstream, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        // A proxy, which passes the dtab of its requests on to the backend.
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let proxy = move |req: Request| {
+            assert_eq!(Dtab::local(), req.dtab);
+            let downstream = downstream.clone();
+            async move { downstream.call(Request::new("/backend".parse().unwrap(), vec![])).await }
+        };
+        let conn = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), proxy).run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+
+        client.send(Message::Tdispatch {
+                        tag: 2,
+                        contexts: vec![],
+                        dst: "/proxy".parse().unwrap(),
+                        dtab: "/backend=>/staging".parse().unwrap(),
+                        req: vec![],
+                    })
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(),
+                   Message::rdispatch_ok(2, b"/backend=>/staging".to_vec()));
+
+        drop(client);
+        conn.await.unwrap().unwrap();
+        dispatcher.await.unwrap().unwrap();
+        backend.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_contexts() {
+        struct TenantId(Vec<u8>);
+
+        impl Context for TenantId {
+            const KEY: &'static str = "com.example.TenantId";
+
+            fn marshal(&self) -> Vec<u8> {
+                self.0.clone()
+            }
+
+            fn unmarshal(bytes: &[u8]) -> Option<TenantId> {
+                Some(TenantId(bytes.to_vec()))
+            }
+        }
+
+        // A backend answering with the tenant of its requests.
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let backend = |_| future::ok(Response::new(context::get::<TenantId>().unwrap().0));
+        let backend = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), backend).run());
+        let (downstream, dispatcher) = Client::new(Framed::new(ours, MuxCodec::new()));
+        let dispatcher = tokio::spawn(dispatcher.run());
+
+        // A proxy, which passes the contexts of its requests on to the backend.
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let proxy = move |_| {
+            let downstream = downstream.clone();
+            async move { downstream.call(Request::new("/backend".parse().unwrap(), vec![])).await }
+        };
+        let conn = tokio::spawn(serve(Framed::new(theirs, MuxCodec::new()), proxy).run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+
+        client.send(Message::Tdispatch {
+                        tag: 2,
+                        contexts: vec![(TenantId::KEY.as_bytes().to_vec(), b"acme".to_vec())],
+                        dst: "/proxy".parse().unwrap(),
+                        dtab: Dtab::empty(),
+                        req: vec![],
+                    })
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(),
+                   Message::rdispatch_ok(2, b"acme".to_vec()));
+
+        drop(client);
+        conn.await.unwrap().unwrap();
+        dispatcher.await.unwrap().unwrap();
+        backend.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lease() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let lessor = QueueDepth::new(1, Duration::from_secs(10));
+        let conn = serve(Framed::new(theirs, MuxCodec::new()), service).with_lessor(lessor);
+        let conn = tokio::spawn(conn.run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+        let lease = |how_long| Message::Tlease { unit: 0, how_long };
+
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(), lease(10_000));
+        // The lease is revoked while the slow request is in flight.
+        client.send(Message::tdispatch(2, "/slow".parse().unwrap(), vec![])).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(), lease(0));
+        client.send(Message::Tdiscarded {
+                        which: 2,
+                        why: "timeout".to_string(),
+                    })
+            .await
+            .unwrap();
+        let mut replies = vec![];
+        for _ in 0..2 {
+            replies.push(client.next().await.unwrap().unwrap().unwrap());
+        }
+        assert_eq!(replies, vec![Message::Rdiscarded { tag: 2 }, lease(10_000)]);
+
+        drop(client);
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_admission_control() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let service = |req: Request| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(Response::new(req.body))
+        };
+        let admission = AdmissionControl::new(1, 1);
+        let conn = serve(Framed::new(theirs, MuxCodec::new()), service)
+            .with_admission_control(admission);
+        let conn = tokio::spawn(conn.run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+
+        // One request is served, one waits and the last one is nacked.
+        for tag in 2..5 {
+            client.send(Message::tdispatch(tag, "/a".parse().unwrap(), vec![tag as u8]))
+                .await
+                .unwrap();
+        }
+        let mut replies = vec![];
+        for _ in 0..3 {
+            replies.push(client.next().await.unwrap().unwrap().unwrap());
+        }
+        let nack = Message::RdispatchNack {
+            tag: 4,
+            contexts: vec![],
+        };
+        let served = [Message::rdispatch_ok(2, vec![2]), Message::rdispatch_ok(3, vec![3])];
+        assert_eq!(replies[0], nack);
+        assert_eq!(replies[1..], served);
+
+        drop(client);
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let conn = serve(Framed::new(theirs, MuxCodec::new()), |req: Request| async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(Response::new(req.body))
+        });
+        let handle = conn.drain_handle();
+        let conn = tokio::spawn(conn.run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+
+        client.send(Message::tdispatch(2, "/a".parse().unwrap(), vec![1])).await.unwrap();
+        ping(&mut client).await;
+        handle.drain(Duration::from_secs(10));
+        match client.next().await {
+            Some(Ok(Ok(Message::Tdrain { tag }))) => {
+                client.send(Message::Rdrain { tag }).await.unwrap()
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+
+        // New requests are nacked, while the outstanding one is answered.
+        client.send(Message::tdispatch(3, "/b".parse().unwrap(), vec![])).await.unwrap();
+        let nack = Message::RdispatchNack {
+            tag: 3,
+            contexts: vec![],
+        };
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(), nack);
+        assert_eq!(client.next().await.unwrap().unwrap().unwrap(),
+                   Message::rdispatch_ok(2, vec![1]));
+        conn.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain_deadline() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let conn = serve(Framed::new(theirs, MuxCodec::new()), service);
+        let handle = conn.drain_handle();
+        let conn = tokio::spawn(conn.run());
+        let mut client = Framed::new(ours, MuxCodec::new());
+
+        client.send(Message::tdispatch(2, "/slow".parse().unwrap(), vec![])).await.unwrap();
+        ping(&mut client).await;
+        handle.drain(Duration::from_secs(1));
+        match client.next().await {
+            Some(Ok(Ok(Message::Tdrain { tag }))) => {
+                client.send(Message::Rdrain { tag }).await.unwrap()
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+        // The slow request is abandoned once the grace period is over.
+        conn.await.unwrap().unwrap();
+        assert!(client.next().await.is_none());
+    }
+}
@@ -0,0 +1,447 @@
+use std::error::Error;
+use std::fmt;
+
+use super::message::{types, DecodeError, Message};
+use super::mux_framer::header;
+
+/**
+ * The mux protocol version spoken by this implementation.
+ */
+pub const VERSION: u16 = 1;
+
+/**
+ * The tag used by the `Tinit` opening a session, and thus by the `Rinit` or
+ * `Rerr` answering it.
+ */
+pub const TINIT_TAG: u32 = 1;
+
+/**
+ * Starts the error of the `Rerr` with which a server rejects a `Tinit`, so
+ * that the client tells it apart from the `Rerr` of a legacy server, which
+ * doesn't know about `Tinit` at all.
+ */
+pub const REJECTED: &str = "mux handshake rejected: ";
+
+pub type Headers = Vec<(Vec<u8>, Vec<u8>)>;
+
+/**
+ * A session feature negotiated through one header of the `Tinit`/`Rinit`
+ * exchange. The client advertises its value for the feature's key; the
+ * server merges its own offer with it into the agreed value, which it sends
+ * back in its `Rinit`. The client takes the agreed value as is.
+ */
+pub trait Feature: Send + Sync {
+    /** The header key this feature is negotiated under. */
+    fn key(&self) -> &[u8];
+
+    /** The value we advertise, or `None` to not advertise the feature. */
+    fn offer(&self) -> Option<Vec<u8>>;
+
+    /**
+     * Merges our offer with the value sent by the peer, which is `None` if
+     * the peer didn't send the header. Returns the agreed value, or `None`
+     * if the feature is not in use for the session.
+     */
+    fn merge(&self, theirs: Option<&[u8]>) -> Result<Option<Vec<u8>>, HandshakeError>;
+}
+
+/**
+ * The set of features negotiated by a handshake.
+ */
+#[derive(Default)]
+pub struct Registry {
+    features: Vec<Box<dyn Feature>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /**
+     * Adds a feature to the registry, replacing any feature registered under
+     * the same key.
+     */
+    pub fn register<F: Feature + 'static>(&mut self, feature: F) -> &mut Registry {
+        self.features.retain(|f| f.key() != feature.key());
+        self.features.push(Box::new(feature));
+        self
+    }
+
+    /** Returns the headers advertising all registered features. */
+    pub fn offer(&self) -> Headers {
+        self.features
+            .iter()
+            .filter_map(|f| f.offer().map(|v| (f.key().to_vec(), v)))
+            .collect()
+    }
+
+    /**
+     * Merges the headers sent by the peer into the agreed value of every
+     * registered feature. Headers no feature is registered for are ignored.
+     */
+    pub fn merge(&self, theirs: &[(Vec<u8>, Vec<u8>)]) -> Result<Headers, HandshakeError> {
+        let mut agreed = Vec::new();
+        for f in &self.features {
+            let value = theirs.iter()
+                .find(|&(k, _)| &k[..] == f.key())
+                .map(|(_, v)| &v[..]);
+            if let Some(v) = f.merge(value)? {
+                agreed.push((f.key().to_vec(), v));
+            }
+        }
+        Ok(agreed)
+    }
+
+    /** Returns whether a feature is registered under `key`. */
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.features.iter().any(|f| f.key() == key)
+    }
+}
+
+/**
+ * The parameters of an established session.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    /**
+     * The protocol version of the peer, or `None` if the peer does not
+     * handshake at all (legacy mode).
+     */
+    pub version: Option<u16>,
+    /**
+     * The maximum body size of the frames written in either direction, or
+     * `None` if messages must not be fragmented.
+     */
+    pub window: Option<u32>,
+    /** The agreed value of every feature in use for the session. */
+    pub agreed: Headers,
+    /** The headers sent by the peer. */
+    pub headers: Headers,
+}
+
+impl Negotiated {
+    fn new(version: u16, agreed: Headers, headers: Headers) -> Negotiated {
+        let window = agreed.iter()
+            .find(|&(k, _)| &k[..] == header::KEY_BUF)
+            .and_then(|(_, v)| header::decode_frame_size(v));
+        Negotiated {
+            version: Some(version),
+            window,
+            agreed,
+            headers,
+        }
+    }
+
+    fn legacy() -> Negotiated {
+        Negotiated {
+            version: None,
+            window: None,
+            agreed: Vec::new(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version.is_none()
+    }
+
+    /** Returns the agreed value of the feature negotiated under `key`. */
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.agreed.iter().find(|&(k, _)| &k[..] == key).map(|(_, v)| &v[..])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeError {
+    /** The peer speaks a protocol version we don't support. */
+    BadVersion(u16),
+    /** The peer sent an invalid value for the given header key. */
+    BadHeader(Vec<u8>),
+    /** The value of the peer for the given header key can't be reconciled with ours. */
+    Incompatible(Vec<u8>),
+    /** The peer sent a message of the given type and tag during the handshake. */
+    UnexpectedMessage { typ: i8, tag: u32 },
+    /** The peer sent a message which can't be decoded during the handshake. */
+    Malformed(DecodeError),
+    /** The server rejected our `Tinit` for the given reason. */
+    Rejected(String),
+}
+
+impl HandshakeError {
+    /** Returns the `Rerr` rejecting the `Tinit` of tag `tag` for this error. */
+    pub fn to_rerr(&self, tag: u32) -> Message {
+        Message::rerr(tag, format!("{}{}", REJECTED, self))
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HandshakeError::BadVersion(version) => {
+                write!(f, "unsupported mux version: {}", version)
+            }
+            HandshakeError::BadHeader(ref key) => {
+                write!(f, "invalid value for header {}", String::from_utf8_lossy(key))
+            }
+            HandshakeError::Incompatible(ref key) => {
+                write!(f, "incompatible value for header {}", String::from_utf8_lossy(key))
+            }
+            HandshakeError::UnexpectedMessage { typ, tag } => {
+                write!(f, "unexpected message during handshake [type={}, tag={}]", typ, tag)
+            }
+            HandshakeError::Malformed(ref e) => {
+                write!(f, "malformed message during handshake: {}", e)
+            }
+            HandshakeError::Rejected(ref reason) => write!(f, "handshake rejected: {}", reason),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    AwaitingRinit,
+    Done,
+}
+
+/**
+ * The client side of the session handshake. The client opens the session
+ * with a `Tinit` and waits for the server's `Rinit`. Older servers which
+ * don't know about `Tinit` answer with an `Rerr`, in which case the session
+ * continues in legacy mode without any negotiated features. A server which
+ * can't agree with our offer answers with an `Rerr` starting with
+ * `REJECTED` instead, which fails the handshake.
+ */
+pub struct ClientHandshake {
+    registry: Registry,
+    state: State,
+}
+
+impl ClientHandshake {
+    /**
+     * Creates a handshake offering the features in `registry`.
+     */
+    pub fn new(registry: Registry) -> ClientHandshake {
+        ClientHandshake {
+            registry,
+            state: State::Idle,
+        }
+    }
+
+    /**
+     * Returns the `Tinit` to open the session with.
+     */
+    pub fn tinit(&mut self) -> Message {
+        assert_eq!(self.state, State::Idle, "Tinit already sent");
+        self.state = State::AwaitingRinit;
+        Message::Tinit {
+            tag: TINIT_TAG,
+            version: VERSION,
+            headers: self.registry.offer(),
+        }
+    }
+
+    /**
+     * Processes the server's answer to our `Tinit`.
+     */
+    pub fn receive(&mut self, msg: Message) -> Result<Negotiated, HandshakeError> {
+        assert_eq!(self.state, State::AwaitingRinit, "Tinit not sent");
+        self.state = State::Done;
+        match msg {
+            Message::Rinit { version, headers, .. } => {
+                if version != VERSION {
+                    return Err(HandshakeError::BadVersion(version));
+                }
+                // The server already merged our offer: its values are final.
+                let agreed = headers.iter()
+                    .filter(|&(k, _)| self.registry.contains(k))
+                    .cloned()
+                    .collect();
+                Ok(Negotiated::new(version, agreed, headers))
+            }
+            Message::Rerr { tag: TINIT_TAG, ref error } if error.starts_with(REJECTED) => {
+                Err(HandshakeError::Rejected(error[REJECTED.len()..].to_string()))
+            }
+            Message::Rerr { tag: TINIT_TAG, .. } => {
+                debug!("peer does not support Tinit, falling back to legacy mode");
+                Ok(Negotiated::legacy())
+            }
+            msg => {
+                Err(HandshakeError::UnexpectedMessage {
+                    typ: msg.typ(),
+                    tag: msg.tag(),
+                })
+            }
+        }
+    }
+}
+
+/**
+ * The server side of the session handshake. A session is negotiated when
+ * the first message of the client is a `Tinit`; any other first message
+ * means that the client does not handshake and must itself be processed as
+ * usual.
+ */
+pub struct ServerHandshake {
+    registry: Registry,
+    state: State,
+}
+
+impl ServerHandshake {
+    /**
+     * Creates a handshake accepting the features in `registry`.
+     */
+    pub fn new(registry: Registry) -> ServerHandshake {
+        ServerHandshake {
+            registry,
+            state: State::Idle,
+        }
+    }
+
+    /**
+     * Processes the first message of the client. Returns the negotiated
+     * session and the `Rinit` to answer with, if any.
+     */
+    pub fn receive(&mut self,
+                   msg: &Message)
+                   -> Result<(Negotiated, Option<Message>), HandshakeError> {
+        assert_eq!(self.state, State::Idle, "handshake already done");
+        self.state = State::Done;
+        match *msg {
+            Message::Tinit { tag, version, ref headers } => {
+                if version != VERSION {
+                    return Err(HandshakeError::BadVersion(version));
+                }
+                let agreed = self.registry.merge(headers)?;
+                let rinit = Message::Rinit {
+                    tag,
+                    version: VERSION,
+                    headers: agreed.clone(),
+                };
+                let negotiated = Negotiated::new(version, agreed, headers.clone());
+                Ok((negotiated, Some(rinit)))
+            }
+            Message::Rinit { .. } => {
+                Err(HandshakeError::UnexpectedMessage {
+                    typ: types::RINIT,
+                    tag: msg.tag(),
+                })
+            }
+            _ => Ok((Negotiated::legacy(), None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::mux_framer::header::{self, FrameSize};
+
+    struct Echo(&'static [u8]);
+
+    impl Feature for Echo {
+        fn key(&self) -> &[u8] {
+            b"echo"
+        }
+
+        fn offer(&self) -> Option<Vec<u8>> {
+            Some(self.0.to_vec())
+        }
+
+        fn merge(&self, theirs: Option<&[u8]>) -> Result<Option<Vec<u8>>, HandshakeError> {
+            Ok(theirs.map(|v| {
+                let mut v = v.to_vec();
+                v.extend_from_slice(self.0);
+                v
+            }))
+        }
+    }
+
+    fn registry(window: Option<u32>) -> Registry {
+        let mut registry = Registry::new();
+        if let Some(window) = window {
+            registry.register(FrameSize(window));
+        }
+        registry
+    }
+
+    #[test]
+    fn test_negotiate_window() {
+        let mut client = ClientHandshake::new(registry(Some(1024)));
+        let mut server = ServerHandshake::new(registry(Some(512)));
+
+        let tinit = client.tinit();
+        let (negotiated, rinit) = server.receive(&tinit).unwrap();
+        assert_eq!(negotiated.version, Some(VERSION));
+        assert_eq!(negotiated.window, Some(512));
+
+        let negotiated = client.receive(rinit.unwrap()).unwrap();
+        assert_eq!(negotiated.window, Some(512));
+    }
+
+    #[test]
+    fn test_no_fragmentation_without_both_headers() {
+        let mut client = ClientHandshake::new(registry(Some(1024)));
+        let mut server = ServerHandshake::new(registry(None));
+        let (negotiated, rinit) = server.receive(&client.tinit()).unwrap();
+        assert_eq!(negotiated.window, None);
+        assert_eq!(client.receive(rinit.unwrap()).unwrap().window, None);
+    }
+
+    #[test]
+    fn test_custom_feature() {
+        let mut ours = registry(Some(1024));
+        ours.register(Echo(b"c"));
+        let mut theirs = registry(Some(1024));
+        theirs.register(Echo(b"s"));
+        let mut client = ClientHandshake::new(ours);
+        let mut server = ServerHandshake::new(theirs);
+
+        let (negotiated, rinit) = server.receive(&client.tinit()).unwrap();
+        assert_eq!(negotiated.get(b"echo"), Some(&b"cs"[..]));
+        // Both sides agree on the value merged by the server.
+        let negotiated = client.receive(rinit.unwrap()).unwrap();
+        assert_eq!(negotiated.get(b"echo"), Some(&b"cs"[..]));
+        assert_eq!(negotiated.window, Some(1024));
+    }
+
+    #[test]
+    fn test_legacy() {
+        let mut client = ClientHandshake::new(registry(Some(1024)));
+        client.tinit();
+        let negotiated = client.receive(Message::rerr(TINIT_TAG, "unknown message".to_string()))
+            .unwrap();
+        assert!(negotiated.is_legacy());
+        assert_eq!(negotiated.window, None);
+
+        let mut server = ServerHandshake::new(registry(Some(1024)));
+        let (negotiated, rinit) = server.receive(&Message::Tping { tag: 2 }).unwrap();
+        assert!(negotiated.is_legacy());
+        assert!(rinit.is_none());
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut server = ServerHandshake::new(registry(Some(1024)));
+        let tinit = Message::Tinit {
+            tag: TINIT_TAG,
+            version: VERSION,
+            headers: vec![(header::KEY_BUF.to_vec(), vec![1, 2])],
+        };
+        assert_eq!(server.receive(&tinit).err(),
+                   Some(HandshakeError::BadHeader(header::KEY_BUF.to_vec())));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let mut client = ClientHandshake::new(registry(Some(1024)));
+        client.tinit();
+        // Not to be mistaken for a legacy server.
+        let rerr = HandshakeError::BadVersion(2).to_rerr(TINIT_TAG);
+        assert_eq!(client.receive(rerr).err(),
+                   Some(HandshakeError::Rejected("unsupported mux version: 2".to_string())));
+    }
+}
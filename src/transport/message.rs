@@ -1,10 +1,11 @@
-use std::io::Cursor;
-use std::io::Read;
+use std::error::Error;
+use std::{fmt, str};
 
-use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-use ::{Dentry, Dtab, Path};
+use byteorder::{ByteOrder, BigEndian};
+use bytes::BufMut;
+use crate::{Dentry, Dtab, NameTree, ParseError, Path, Prefix};
 
-mod types {
+pub mod types {
     // Application messages:
     pub const TREQ: i8 = 1;
     pub const RREQ: i8 = -1;
@@ -33,15 +34,15 @@ mod types {
     pub const BAD_RERR: i8 = 127;
 }
 
-mod tags {
+pub mod tags {
     pub const MARKER_TAG: u32 = 0;
     // We reserve a tag for a default ping message so that we
     // can cache a full ping message and avoid encoding it
     // every time.
     pub const PING_TAG: u32 = 1;
-    const MIN_TAG: u32 = PING_TAG + 1;
+    pub const MIN_TAG: u32 = PING_TAG + 1;
     pub const MAX_TAG: u32 = (1 << 23) - 1;
-    pub const TAG_MSB: u32 = (1 << 23);
+    pub const TAG_MSB: u32 = 1 << 23;
 
     pub fn extract_type(header: u32) -> i8 {
         (header >> 24 & 0xff) as i8
@@ -55,47 +56,45 @@ mod tags {
         (tag >> 23 & 1) == 1
     }
 
-    fn set_msb(tag: u32) -> u32 {
+    pub fn set_msb(tag: u32) -> u32 {
         tag | TAG_MSB
     }
 }
 
+/** The key-value pairs of the contexts and handshake headers. */
+type KeyValues = Vec<(Vec<u8>, Vec<u8>)>;
+
 mod init {
-    use std::io::Cursor;
-    use std::io::Read;
-    use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+    use bytes::BufMut;
+    use super::{DecodeError, KeyValues, Reader};
+    #[cfg(test)]
+    use super::types::TINIT;
 
-    pub fn encode(version: u16, headers: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
-        let mut buf = Vec::new();
-        buf.write_u16::<BigEndian>(version).unwrap();
-        for pair in &headers {
-            let k = &pair.0;
-            let v = &pair.1;
-            buf.write_u32::<BigEndian>(k.len() as u32).unwrap();
-            buf.extend_from_slice(&k);
-            buf.write_u32::<BigEndian>(v.len() as u32).unwrap();
-            buf.extend_from_slice(&v);
+    pub fn encoded_len(headers: &[(Vec<u8>, Vec<u8>)]) -> usize {
+        headers.iter().fold(2, |n, (k, v)| n + 8 + k.len() + v.len())
+    }
+
+    pub fn encode_into<B: BufMut>(version: u16, headers: &[(Vec<u8>, Vec<u8>)], buf: &mut B) {
+        buf.put_u16(version);
+        for (k, v) in headers {
+            buf.put_u32(k.len() as u32);
+            buf.put_slice(k);
+            buf.put_u32(v.len() as u32);
+            buf.put_slice(v);
         }
-        buf
     }
 
-    pub fn decode(buf: Vec<u8>) -> (u16, Vec<(Vec<u8>, Vec<u8>)>) {
-        let len = buf.len() as u64;
-        let mut rdr = Cursor::new(buf);
-        let version = rdr.read_u16::<BigEndian>().unwrap();
+    pub fn decode(rdr: &mut Reader) -> Result<(u16, KeyValues), DecodeError> {
+        let version = rdr.read_u16()?;
         let mut headers: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-        while rdr.position() < len {
-            let kl = rdr.read_u32::<BigEndian>().unwrap() as usize;
-            let mut k: Vec<u8> = Vec::new();
-            k.resize(kl, 0);
-            rdr.read_exact(&mut k).unwrap();
-            let vl = rdr.read_u32::<BigEndian>().unwrap() as usize;
-            let mut v: Vec<u8> = Vec::new();
-            v.resize(vl, 0);
-            rdr.read_exact(&mut v).unwrap();
+        while rdr.remaining() > 0 {
+            let kl = rdr.read_u32()? as usize;
+            let k = rdr.read_bytes(kl)?.to_vec();
+            let vl = rdr.read_u32()? as usize;
+            let v = rdr.read_bytes(vl)?.to_vec();
             headers.push((k, v));
         }
-        (version, headers)
+        Ok((version, headers))
     }
 
     #[test]
@@ -104,19 +103,30 @@ mod init {
         let headers = vec![(vec![1], vec![2, 3]),
                            (vec![4, 5, 6], vec![7, 8, 9, 10]),
                            (vec![11, 12, 13], vec![14, 15])];
-        let buf = encode(version, headers.clone());
-        let (got_version, got_headers) = decode(buf);
+        let mut buf = Vec::new();
+        encode_into(version, &headers, &mut buf);
+        assert_eq!(buf.len(), encoded_len(&headers));
+        let (got_version, got_headers) = decode(&mut Reader::new(&buf, TINIT, 0)).unwrap();
         assert_eq!(version, got_version);
         assert_eq!(headers, got_headers);
     }
 }
 
-enum Message {
+/**
+ * A mux protocol message. Each message carries a type and a tag which are
+ * encoded into the first four bytes on the wire; the remaining bytes form
+ * the message body. Use `encode` and `decode` to convert between messages
+ * and their wire representation.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /** Initializes a session, advertising a protocol version and headers. */
     Tinit {
         tag: u32,
         version: u16,
         headers: Vec<(Vec<u8>, Vec<u8>)>,
     },
+    /** A reply to a `Tinit` message */
     Rinit {
         tag: u32,
         version: u16,
@@ -137,7 +147,12 @@ enum Message {
      */
     RreqOk { tag: u32, reply: Vec<u8> },
     RreqError { tag: u32, error: String },
+    /** Indicates that the `Treq` was rejected without being processed. */
     RreqNack { tag: u32 },
+    /**
+     * A transmit request carrying broadcast contexts, a destination path
+     * and a delegation table alongside the request body.
+     */
     Tdispatch {
         tag: u32,
         contexts: Vec<(Vec<u8>, Vec<u8>)>,
@@ -151,11 +166,13 @@ enum Message {
         contexts: Vec<(Vec<u8>, Vec<u8>)>,
         reply: Vec<u8>,
     },
+    /** An application error in reply to a `Tdispatch` message */
     RdispatchError {
         tag: u32,
         contexts: Vec<(Vec<u8>, Vec<u8>)>,
         error: String,
     },
+    /** Indicates that the `Tdispatch` was rejected without being processed. */
     RdispatchNack {
         tag: u32,
         contexts: Vec<(Vec<u8>, Vec<u8>)>,
@@ -186,12 +203,58 @@ enum Message {
      * by the client.
      */
     Tdiscarded { which: u32, why: String },
+    /** Response to a `Tdiscarded` message */
     Rdiscarded { tag: u32 },
+    /**
+     * Grants the client a lease of `how_long` units, during which the server
+     * expects to accept requests.
+     */
     Tlease { unit: u8, how_long: u64 },
 }
 
 impl Message {
-    fn typ(&self) -> i8 {
+    /** Creates a `Tdispatch` without contexts or dtab overrides. */
+    pub fn tdispatch(tag: u32, dst: Path, req: Vec<u8>) -> Message {
+        Message::Tdispatch {
+            tag,
+            contexts: Vec::new(),
+            dst,
+            dtab: Dtab::empty(),
+            req,
+        }
+    }
+
+    /** Creates a successful `Rdispatch` without contexts. */
+    pub fn rdispatch_ok(tag: u32, reply: Vec<u8>) -> Message {
+        Message::RdispatchOk {
+            tag,
+            contexts: Vec::new(),
+            reply,
+        }
+    }
+
+    /** Creates a failed `Rdispatch` without contexts. */
+    pub fn rdispatch_error(tag: u32, error: String) -> Message {
+        Message::RdispatchError {
+            tag,
+            contexts: Vec::new(),
+            error,
+        }
+    }
+
+    /** Creates an `Rerr` reporting that the message with `tag` failed. */
+    pub fn rerr(tag: u32, error: String) -> Message {
+        Message::Rerr {
+            tag,
+            error,
+        }
+    }
+
+    /**
+     * Returns the wire type of this message. Note that `Rerr` and
+     * `Tdiscarded` report their legacy types, which is what gets encoded.
+     */
+    pub fn typ(&self) -> i8 {
         match *self {
             Message::Tinit { .. } => types::TINIT,
             Message::Rinit { .. } => types::RINIT,
@@ -206,7 +269,7 @@ impl Message {
             Message::Fragment { typ, .. } => typ,
             Message::Tdrain { .. } => types::TDRAIN,
             Message::Rdrain { .. } => types::RDRAIN,
-            Message::Tping { .. } => types::TPING,
+            Message::Tping { .. } | Message::PreEncodedTping => types::TPING,
             Message::Rping { .. } => types::RPING,
             // Use the old Rerr type in a transition period so that we
             // can be reasonably sure we remain backwards compatible with
@@ -218,11 +281,14 @@ impl Message {
             Message::Tdiscarded { .. } => types::BAD_TDISCARDED,
             Message::Rdiscarded { .. } => types::RDISCARDED,
             Message::Tlease { .. } => types::TLEASE,
-            Message::PreEncodedTping => 0,
         }
     }
 
-    fn tag(&self) -> u32 {
+    /**
+     * Returns the tag of this message. Messages which do not carry a tag,
+     * such as `Tdiscarded` and `Tlease`, report the marker tag.
+     */
+    pub fn tag(&self) -> u32 {
         match *self {
             Message::Tinit { tag, .. } |
             Message::Rinit { tag, .. } |
@@ -243,375 +309,1131 @@ impl Message {
             Message::Rdiscarded { tag } => tag,
             Message::Tdiscarded { .. } |
             Message::Tlease { .. } => 0,
-            Message::PreEncodedTping => 0,
+            Message::PreEncodedTping => tags::PING_TAG,
+        }
+    }
+
+    /** Returns the encoded body of this message, i.e. everything after the header. */
+    pub fn buf(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.body_len());
+        self.write_body(&mut buf);
+        buf
+    }
+
+    /** Returns the exact size of the encoded body of this message. */
+    pub fn body_len(&self) -> usize {
+        match *self {
+            Message::Tinit { ref headers, .. } |
+            Message::Rinit { ref headers, .. } => init::encoded_len(headers),
+            Message::Treq { ref req, .. } => 1 + req.len(),
+            Message::RreqOk { ref reply, .. } => 1 + reply.len(),
+            Message::RreqError { ref error, .. } => 1 + error.len(),
+            Message::RreqNack { .. } => 1,
+            Message::Tdispatch { ref contexts, ref dst, ref dtab, ref req, .. } => {
+                let dtab_len = dtab.iter()
+                    .fold(0, |n, d| n + 4 + display_len(&d.prefix) + display_len(&d.dst));
+                contexts_len(contexts) + 2 + display_len(dst) + 2 + dtab_len + req.len()
+            }
+            Message::RdispatchOk { ref contexts, ref reply, .. } => {
+                1 + contexts_len(contexts) + reply.len()
+            }
+            Message::RdispatchError { ref contexts, ref error, .. } => {
+                1 + contexts_len(contexts) + error.len()
+            }
+            Message::RdispatchNack { ref contexts, .. } => 1 + contexts_len(contexts),
+            Message::Fragment { ref buf, .. } => buf.len(),
+            Message::Tdrain { .. } |
+            Message::Rdrain { .. } |
+            Message::Tping { .. } |
+            Message::PreEncodedTping |
+            Message::Rping { .. } |
+            Message::Rdiscarded { .. } => 0,
+            Message::Rerr { ref error, .. } => error.len(),
+            Message::Tdiscarded { ref why, .. } => 3 + why.len(),
+            Message::Tlease { .. } => 9,
         }
     }
 
-    fn buf(&self) -> Vec<u8> {
+    /**
+     * Writes the encoded body of this message into `buf`. Payloads are
+     * copied straight from the message, without intermediate buffers.
+     */
+    pub fn write_body<B: BufMut>(&self, buf: &mut B) {
         match *self {
-            Message::Tinit { version, ref headers, .. } => init::encode(version, headers.clone()),
-            Message::Rinit { version, ref headers, .. } => init::encode(version, headers.clone()),
+            Message::Tinit { version, ref headers, .. } |
+            Message::Rinit { version, ref headers, .. } => init::encode_into(version, headers, buf),
             Message::Treq { ref req, .. } => {
-                let mut vec = vec![0];
-                vec.extend_from_slice(&req[..]);
-                vec
+                buf.put_u8(0);
+                buf.put_slice(req);
             }
             Message::RreqOk { ref reply, .. } => {
-                let mut vec = vec![0];
-                vec.extend_from_slice(&reply[..]);
-                vec
+                buf.put_u8(0);
+                buf.put_slice(reply);
             }
             Message::RreqError { ref error, .. } => {
-                let mut vec = vec![1];
-                let bytes = error.clone().into_bytes();
-                vec.extend_from_slice(&bytes[..]);
-                vec
+                buf.put_u8(1);
+                buf.put_slice(error.as_bytes());
             }
-            Message::RreqNack { .. } => vec![2],
+            Message::RreqNack { .. } => buf.put_u8(2),
             Message::Tdispatch { ref contexts, ref dst, ref dtab, ref req, .. } => {
-                let mut buf = Vec::new();
-                buf.write_u16::<BigEndian>(contexts.len() as u16).unwrap();
-                for pair in contexts {
-                    let k = &pair.0;
-                    let v = &pair.1;
-                    buf.write_u16::<BigEndian>(k.len() as u16).unwrap();
-                    buf.extend_from_slice(&k);
-                    buf.write_u16::<BigEndian>(v.len() as u16).unwrap();
-                    buf.extend_from_slice(&v);
-                }
+                write_contexts(contexts, buf);
 
-                buf.write_u16::<BigEndian>(dst.len() as u16).unwrap();
-                let bytes = dst.clone().into_bytes();
-                buf.extend_from_slice(&bytes[..]);
+                write_display(dst, buf);
 
-                buf.write_u16::<BigEndian>(dtab.len() as u16).unwrap();
+                write_len(dtab.len(), "dtab", buf);
                 for dentry in dtab {
-                    let srcbytes = dentry.prefix.clone().into_bytes();
-                    let treebytes = dentry.dst.clone().into_bytes();
-                    buf.write_u16::<BigEndian>(srcbytes.len() as u16).unwrap();
-                    buf.extend_from_slice(&srcbytes);
-                    buf.write_u16::<BigEndian>(treebytes.len() as u16).unwrap();
-                    buf.extend_from_slice(&treebytes);
+                    write_display(&dentry.prefix, buf);
+                    write_display(&dentry.dst, buf);
                 }
-                buf.extend_from_slice(&req[..]);
-                buf
+                buf.put_slice(req);
             }
             Message::RdispatchOk { ref contexts, ref reply, .. } => {
-                let mut buf = Vec::new();
-                buf.push(0u8);
-                buf.write_u16::<BigEndian>(contexts.len() as u16).unwrap();
-                for pair in contexts {
-                    let k = &pair.0;
-                    let v = &pair.1;
-                    buf.write_u16::<BigEndian>(k.len() as u16).unwrap();
-                    buf.extend_from_slice(&k);
-                    buf.write_u16::<BigEndian>(v.len() as u16).unwrap();
-                    buf.extend_from_slice(&v);
-                }
-                buf.extend_from_slice(&reply[..]);
-                buf
+                buf.put_u8(0);
+                write_contexts(contexts, buf);
+                buf.put_slice(reply);
             }
             Message::RdispatchError { ref contexts, ref error, .. } => {
-                let mut buf = Vec::new();
-                buf.push(1u8);
-                buf.write_u16::<BigEndian>(contexts.len() as u16).unwrap();
-                for pair in contexts {
-                    let k = &pair.0;
-                    let v = &pair.1;
-                    buf.write_u16::<BigEndian>(k.len() as u16).unwrap();
-                    buf.extend_from_slice(&k);
-                    buf.write_u16::<BigEndian>(v.len() as u16).unwrap();
-                    buf.extend_from_slice(&v);
-                }
-                let bytes = error.clone().into_bytes();
-                buf.extend_from_slice(&bytes[..]);
-                buf
+                buf.put_u8(1);
+                write_contexts(contexts, buf);
+                buf.put_slice(error.as_bytes());
             }
             Message::RdispatchNack { ref contexts, .. } => {
-                let mut buf = Vec::new();
-                buf.push(2u8);
-                buf.write_u16::<BigEndian>(contexts.len() as u16).unwrap();
-                for pair in contexts {
-                    let k = &pair.0;
-                    let v = &pair.1;
-                    buf.write_u16::<BigEndian>(k.len() as u16).unwrap();
-                    buf.extend_from_slice(&k);
-                    buf.write_u16::<BigEndian>(v.len() as u16).unwrap();
-                    buf.extend_from_slice(&v);
-                }
-                buf
+                buf.put_u8(2);
+                write_contexts(contexts, buf);
             }
-            Message::Fragment { ref buf, .. } => buf.clone(),
+            Message::Fragment { buf: ref body, .. } => buf.put_slice(body),
             Message::Tdrain { .. } |
             Message::Rdrain { .. } |
             Message::Tping { .. } |
+            Message::PreEncodedTping |
             Message::Rping { .. } |
-            Message::Rdiscarded { .. } => vec![],
-            Message::Rerr { ref error, .. } => error.clone().into_bytes(),
+            Message::Rdiscarded { .. } => {}
+            Message::Rerr { ref error, .. } => buf.put_slice(error.as_bytes()),
             Message::Tdiscarded { which, ref why } => {
-                let mut arr = vec![(which >> 16 & 0xff) as u8,
-                                   (which >> 8 & 0xff) as u8,
-                                   (which & 0xff) as u8];
-                let why = why.clone().into_bytes();
-                arr.extend_from_slice(&why[..]);
-                arr
+                buf.put_u8((which >> 16 & 0xff) as u8);
+                buf.put_u8((which >> 8 & 0xff) as u8);
+                buf.put_u8((which & 0xff) as u8);
+                buf.put_slice(why.as_bytes());
             }
             Message::Tlease { unit, how_long } => {
-                let mut buf = Vec::new();
-                buf.push(unit);
-                buf.write_u64::<BigEndian>(how_long).unwrap();
-                buf
+                buf.put_u8(unit);
+                buf.put_u64(how_long);
             }
-            Message::PreEncodedTping => encode(Message::Tping { tag: tags::PING_TAG }),
         }
     }
 }
 
-fn decode_treq(tag: u32, buf: Vec<u8>) -> Message {
-    if buf.len() < 1 {
-        panic!("short Treq");
+fn contexts_len(contexts: &[(Vec<u8>, Vec<u8>)]) -> usize {
+    contexts.iter().fold(2, |n, (k, v)| n + 4 + k.len() + v.len())
+}
+
+fn write_contexts<B: BufMut>(contexts: &[(Vec<u8>, Vec<u8>)], buf: &mut B) {
+    write_len(contexts.len(), "contexts", buf);
+    for (k, v) in contexts {
+        write_len(k.len(), "context key", buf);
+        buf.put_slice(k);
+        write_len(v.len(), "context value", buf);
+        buf.put_slice(v);
     }
+}
 
-    let mut rdr = Cursor::new(buf);
-    let mut nkeys = [0u8];
-    rdr.read_exact(&mut nkeys).unwrap();
-    if nkeys[0] != 0 {
-        panic!("Treq: too many keys");
+/**
+ * Writes the 16 bit length of a field. Panics if the length doesn't fit, as
+ * the message can't be encoded.
+ */
+fn write_len<B: BufMut>(len: usize, field: &str, buf: &mut B) {
+    match u16::try_from(len) {
+        Ok(len) => buf.put_u16(len),
+        Err(_) => panic!("{} of length {} exceeds {}", field, len, u16::MAX),
     }
-    let mut req: Vec<u8> = Vec::new();
-    rdr.read_to_end(&mut req).unwrap();
-    Message::Treq {
-        tag: tag,
-        req: req,
+}
+
+/** Counts the bytes written to it. */
+struct Counter(usize);
+
+impl fmt::Write for Counter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
     }
 }
 
-fn decode_contexts(rdr: &mut Cursor<Vec<u8>>) -> Vec<(Vec<u8>, Vec<u8>)> {
-    let mut contexts: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
-    let mut n = rdr.read_u16::<BigEndian>().unwrap();
-    if n == 0 {
-        return contexts;
+/** Writes strings straight into a buffer. */
+struct Writer<'a, B>(&'a mut B);
+
+impl<B: BufMut> fmt::Write for Writer<'_, B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.put_slice(s.as_bytes());
+        Ok(())
+    }
+}
+
+/** Returns the length of `name` as displayed, without rendering it. */
+fn display_len<D: fmt::Display>(name: &D) -> usize {
+    let mut counter = Counter(0);
+    fmt::write(&mut counter, format_args!("{}", name)).unwrap();
+    counter.0
+}
+
+/** Writes `name` as a string prefixed with its length. */
+fn write_display<D: fmt::Display, B: BufMut>(name: &D, buf: &mut B) {
+    write_len(display_len(name), "name", buf);
+    fmt::write(&mut Writer(buf), format_args!("{}", name)).unwrap();
+}
+
+/**
+ * Describes why a buffer could not be decoded into a `Message`. Every variant
+ * carries the type and tag of the offending message (as far as they could be
+ * read) along with the byte offset into the message at which decoding failed.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /** The buffer ended before the message was complete. */
+    Short { typ: i8, tag: u32, offset: usize },
+    /** A string field does not contain valid UTF-8. */
+    BadUtf8 { typ: i8, tag: u32, offset: usize },
+    /** The message type is not known to this implementation. */
+    UnknownType { typ: i8, tag: u32 },
+    /** An `Rreq` or `Rdispatch` carries a status other than ok, error or nack. */
+    BadStatus {
+        typ: i8,
+        tag: u32,
+        offset: usize,
+        status: u8,
+    },
+    /** A `Treq` carries trace keys, which are no longer supported. */
+    TreqKeys { tag: u32, offset: usize, nkeys: u8 },
+    /** A `Tdispatch` destination or dtab entry is not valid syntax. */
+    BadName {
+        tag: u32,
+        offset: usize,
+        error: ParseError,
+    },
+}
+
+impl DecodeError {
+    pub fn typ(&self) -> i8 {
+        match *self {
+            DecodeError::Short { typ, .. } |
+            DecodeError::BadUtf8 { typ, .. } |
+            DecodeError::UnknownType { typ, .. } |
+            DecodeError::BadStatus { typ, .. } => typ,
+            DecodeError::TreqKeys { .. } => types::TREQ,
+            DecodeError::BadName { .. } => types::TDISPATCH,
+        }
+    }
+
+    pub fn tag(&self) -> u32 {
+        match *self {
+            DecodeError::Short { tag, .. } |
+            DecodeError::BadUtf8 { tag, .. } |
+            DecodeError::UnknownType { tag, .. } |
+            DecodeError::BadStatus { tag, .. } |
+            DecodeError::TreqKeys { tag, .. } |
+            DecodeError::BadName { tag, .. } => tag,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        match *self {
+            DecodeError::Short { offset, .. } |
+            DecodeError::BadUtf8 { offset, .. } |
+            DecodeError::BadStatus { offset, .. } |
+            DecodeError::TreqKeys { offset, .. } |
+            DecodeError::BadName { offset, .. } => offset,
+            DecodeError::UnknownType { .. } => 0,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Short { typ, tag, offset } => {
+                write!(f, "short message [type={}, tag={}, offset={}]", typ, tag, offset)
+            }
+            DecodeError::BadUtf8 { typ, tag, offset } => {
+                write!(f, "invalid UTF-8 [type={}, tag={}, offset={}]", typ, tag, offset)
+            }
+            DecodeError::UnknownType { typ, tag } => {
+                write!(f, "unknown message type: {} [tag={}]", typ, tag)
+            }
+            DecodeError::BadStatus { typ, tag, offset, status } => {
+                write!(f,
+                       "invalid status {} [type={}, tag={}, offset={}]",
+                       status,
+                       typ,
+                       tag,
+                       offset)
+            }
+            DecodeError::TreqKeys { tag, offset, nkeys } => {
+                write!(f, "Treq: too many keys ({}) [tag={}, offset={}]", nkeys, tag, offset)
+            }
+            DecodeError::BadName { tag, offset, ref error } => {
+                write!(f, "Tdispatch: {} [tag={}, offset={}]", error, tag, offset)
+            }
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/**
+ * A cursor over a complete message which remembers the type and tag being
+ * decoded so that failures can be reported with their context.
+ */
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    typ: i8,
+    tag: u32,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], typ: i8, tag: u32) -> Reader<'a> {
+        Reader {
+            buf,
+            pos: 0,
+            typ,
+            tag,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn short(&self) -> DecodeError {
+        DecodeError::Short {
+            typ: self.typ,
+            tag: self.tag,
+            offset: self.pos,
+        }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.remaining() < n {
+            return Err(self.short());
+        }
+        let bytes = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn read_rest(&mut self) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        bytes
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        self.read_bytes(1).map(|b| b[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        self.read_bytes(2).map(BigEndian::read_u16)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        self.read_bytes(4).map(BigEndian::read_u32)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        self.read_bytes(8).map(BigEndian::read_u64)
+    }
+
+    fn str(&self, offset: usize, bytes: &'a [u8]) -> Result<&'a str, DecodeError> {
+        str::from_utf8(bytes).map_err(|e| {
+            DecodeError::BadUtf8 {
+                typ: self.typ,
+                tag: self.tag,
+                offset: offset + e.valid_up_to(),
+            }
+        })
+    }
+
+    fn read_str(&mut self, n: usize) -> Result<&'a str, DecodeError> {
+        let offset = self.pos;
+        let bytes = self.read_bytes(n)?;
+        self.str(offset, bytes)
+    }
+
+    /** Reads a string of `n` bytes, parsing it with `read`. */
+    fn read_name<T>(&mut self,
+                    n: usize,
+                    read: fn(&str) -> Result<T, ParseError>)
+                    -> Result<T, DecodeError> {
+        let offset = self.pos;
+        let s = self.read_str(n)?;
+        read(s).map_err(|error| {
+            DecodeError::BadName {
+                tag: self.tag,
+                offset,
+                error,
+            }
+        })
+    }
+
+    fn read_rest_string(&mut self) -> Result<String, DecodeError> {
+        let offset = self.pos;
+        let bytes = self.read_rest();
+        self.str(offset, bytes).map(|s| s.to_string())
+    }
+}
+
+fn decode_treq(rdr: &mut Reader) -> Result<Message, DecodeError> {
+    let offset = rdr.pos;
+    let nkeys = rdr.read_u8()?;
+    if nkeys != 0 {
+        return Err(DecodeError::TreqKeys {
+            tag: rdr.tag,
+            offset,
+            nkeys,
+        });
     }
+    Ok(Message::Treq {
+        tag: rdr.tag,
+        req: rdr.read_rest().to_vec(),
+    })
+}
+
+fn decode_contexts(rdr: &mut Reader) -> Result<KeyValues, DecodeError> {
+    let mut contexts: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut n = rdr.read_u16()?;
     while n > 0 {
-        let kl = rdr.read_u16::<BigEndian>().unwrap() as usize;
-        let mut k: Vec<u8> = Vec::new();
-        k.resize(kl, 0);
-        rdr.read_exact(&mut k).unwrap();
-        let vl = rdr.read_u16::<BigEndian>().unwrap() as usize;
-        let mut v: Vec<u8> = Vec::new();
-        v.resize(vl, 0);
-        rdr.read_exact(&mut v).unwrap();
+        let kl = rdr.read_u16()? as usize;
+        let k = rdr.read_bytes(kl)?.to_vec();
+        let vl = rdr.read_u16()? as usize;
+        let v = rdr.read_bytes(vl)?.to_vec();
         contexts.push((k, v));
         n -= 1;
     }
-    contexts
-}
-
-fn decode_tdispatch(tag: u32, buf: Vec<u8>) -> Message {
-    let mut rdr = Cursor::new(buf);
-    let contexts = decode_contexts(&mut rdr);
-    let ndst = rdr.read_u16::<BigEndian>().unwrap();
-    let mut dst = String::new();
-    if ndst > 0 {
-        let mut s: Vec<u8> = Vec::new();
-        s.resize(ndst as usize, 0);
-        rdr.read_exact(&mut s).unwrap();
-        dst = String::from_utf8(s).unwrap();
-    }
-
-    let mut nd = rdr.read_u16::<BigEndian>().unwrap();
-    let mut dtab: Dtab = Vec::new();
-    while nd > 0 {
-        let sl = rdr.read_u16::<BigEndian>().unwrap() as usize;
-        let mut s: Vec<u8> = Vec::new();
-        s.resize(sl as usize, 0);
-        rdr.read_exact(&mut s).unwrap();
-        let src = String::from_utf8(s).unwrap();
-        let dl = rdr.read_u16::<BigEndian>().unwrap() as usize;
-        let mut d: Vec<u8> = Vec::new();
-        d.resize(dl as usize, 0);
-        rdr.read_exact(&mut d).unwrap();
-        let dst = String::from_utf8(d).unwrap();
-        dtab.push(Dentry {
-            prefix: src,
-            dst: dst,
-        });
-        nd -= 1;
-    }
-    let mut req: Vec<u8> = Vec::new();
-    rdr.read_to_end(&mut req).unwrap();
-    Message::Tdispatch {
-        tag: tag,
-        contexts: contexts,
-        dst: dst,
-        dtab: dtab,
-        req: req,
-    }
-}
-
-fn decode_rdispatch(tag: u32, buf: Vec<u8>) -> Message {
-    let mut rdr = Cursor::new(buf);
-    let mut status = [0u8];
-    rdr.read_exact(&mut status).unwrap();
-    let contexts = decode_contexts(&mut rdr);
-    let mut rest: Vec<u8> = Vec::new();
-    rdr.read_to_end(&mut rest).unwrap();
-    match status[0] {
+    Ok(contexts)
+}
+
+fn decode_tdispatch(rdr: &mut Reader) -> Result<Message, DecodeError> {
+    let contexts = decode_contexts(rdr)?;
+    let ndst = rdr.read_u16()? as usize;
+    let dst = rdr.read_name(ndst, read_dst)?;
+    let nd = rdr.read_u16()?;
+    let dtab = decode_dtab(rdr, nd)?;
+    Ok(Message::Tdispatch {
+        tag: rdr.tag,
+        contexts,
+        dst,
+        dtab,
+        req: rdr.read_rest().to_vec(),
+    })
+}
+
+/** Reads a destination path, which some clients leave empty. */
+fn read_dst(s: &str) -> Result<Path, ParseError> {
+    if s.is_empty() {
+        Ok(Path::empty())
+    } else {
+        Path::read(s)
+    }
+}
+
+fn decode_dtab(rdr: &mut Reader, n: u16) -> Result<Dtab, DecodeError> {
+    let mut dtab = Dtab::empty();
+    for _ in 0..n {
+        let pl = rdr.read_u16()? as usize;
+        let prefix = rdr.read_name(pl, Prefix::read)?;
+        let dl = rdr.read_u16()? as usize;
+        let dst = rdr.read_name(dl, NameTree::read)?;
+        dtab.push(Dentry::new(prefix, dst));
+    }
+    Ok(dtab)
+}
+
+fn decode_rdispatch(rdr: &mut Reader) -> Result<Message, DecodeError> {
+    let offset = rdr.pos;
+    let status = rdr.read_u8()?;
+    let contexts = decode_contexts(rdr)?;
+    match status {
         0 => {
-            Message::RdispatchOk {
-                tag: tag,
-                contexts: contexts,
-                reply: rest,
-            }
+            Ok(Message::RdispatchOk {
+                tag: rdr.tag,
+                contexts,
+                reply: rdr.read_rest().to_vec(),
+            })
         }
         1 => {
-            Message::RdispatchError {
-                tag: tag,
-                contexts: contexts,
-                error: String::from_utf8(rest).unwrap(),
-            }
+            Ok(Message::RdispatchError {
+                tag: rdr.tag,
+                contexts,
+                error: rdr.read_rest_string()?,
+            })
         }
         2 => {
-            Message::RdispatchNack {
-                tag: tag,
-                contexts: contexts,
-            }
+            Ok(Message::RdispatchNack {
+                tag: rdr.tag,
+                contexts,
+            })
+        }
+        _ => {
+            Err(DecodeError::BadStatus {
+                typ: rdr.typ,
+                tag: rdr.tag,
+                offset,
+                status,
+            })
         }
-        _ => panic!("invalid Rdispatch status"),
     }
 }
 
-fn decode_rreq(tag: u32, buf: Vec<u8>) -> Message {
-    if buf.len() < 1 {
-        panic!("short Rreq");
-    }
-    let mut rdr = Cursor::new(buf);
-    let mut status = [0u8];
-    rdr.read_exact(&mut status).unwrap();
-    let mut rest: Vec<u8> = Vec::new();
-    rdr.read_to_end(&mut rest).unwrap();
-    match status[0] {
+fn decode_rreq(rdr: &mut Reader) -> Result<Message, DecodeError> {
+    let offset = rdr.pos;
+    let status = rdr.read_u8()?;
+    match status {
         0 => {
-            Message::RreqOk {
-                tag: tag,
-                reply: rest,
-            }
+            Ok(Message::RreqOk {
+                tag: rdr.tag,
+                reply: rdr.read_rest().to_vec(),
+            })
         }
         1 => {
-            Message::RreqError {
-                tag: tag,
-                error: String::from_utf8(rest).unwrap(),
-            }
+            Ok(Message::RreqError {
+                tag: rdr.tag,
+                error: rdr.read_rest_string()?,
+            })
+        }
+        2 => Ok(Message::RreqNack { tag: rdr.tag }),
+        _ => {
+            Err(DecodeError::BadStatus {
+                typ: rdr.typ,
+                tag: rdr.tag,
+                offset,
+                status,
+            })
         }
-        2 => Message::RreqNack { tag: tag },
-        _ => panic!("invalid Rreq status"),
     }
 }
 
-fn decode_tdiscarded(buf: Vec<u8>) -> Message {
-    if buf.len() < 3 {
-        panic!("short Tdiscarded message");
-    }
-    let mut rdr = Cursor::new(buf);
-    let mut bytes = [0; 3];
-    rdr.read_exact(&mut bytes).unwrap();
-    let which: u32 = (((bytes[0] & 0xff) as u32) << 16) | (((bytes[1] & 0xff) as u32) << 8) |
-                     (bytes[2] & 0xff) as u32;
-    let mut why: Vec<u8> = Vec::new();
-    rdr.read_to_end(&mut why).unwrap();
-    Message::Tdiscarded {
-        which: which,
-        why: String::from_utf8(why).unwrap(),
-    }
+fn decode_tdiscarded(rdr: &mut Reader) -> Result<Message, DecodeError> {
+    let bytes = rdr.read_bytes(3)?;
+    let which: u32 = ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32;
+    Ok(Message::Tdiscarded {
+        which,
+        why: rdr.read_rest_string()?,
+    })
 }
 
-fn decode_tlease(buf: Vec<u8>) -> Message {
-    if buf.len() < 9 {
-        panic!("short Tlease message");
-    }
-    let mut rdr = Cursor::new(buf);
-    let mut unit = [0u8];
-    rdr.read_exact(&mut unit).unwrap();
-    let how_much = rdr.read_u64::<BigEndian>().unwrap();
-    Message::Tlease {
-        unit: unit[0],
-        how_long: how_much,
-    }
+fn decode_tlease(rdr: &mut Reader) -> Result<Message, DecodeError> {
+    let unit = rdr.read_u8()?;
+    let how_long = rdr.read_u64()?;
+    Ok(Message::Tlease {
+        unit,
+        how_long,
+    })
 }
 
-fn decode(buf: Vec<u8>) -> Message {
-    if buf.len() < 4 {
-        panic!("short message");
-    }
-    let mut rdr = Cursor::new(buf);
-    let head = rdr.read_u32::<BigEndian>().unwrap();
-    let mut rest: Vec<u8> = Vec::new();
-    rdr.read_to_end(&mut rest).unwrap();
-    let typ = tags::extract_type(head);
-    let tag = tags::extract_tag(head);
+/**
+ * Decodes a complete mux message (without its size prefix). Malformed input
+ * is reported as a `DecodeError` rather than a panic, since the buffer
+ * usually comes straight off the network.
+ *
+ * The destination and dtab of a `Tdispatch` are parsed strictly: a single
+ * invalid name fails the whole message with `BadName`, which a server
+ * answers with an `Rerr`. `decode_ref` leaves names unparsed, so the rest of
+ * such a message stays readable.
+ */
+pub fn decode(buf: Vec<u8>) -> Result<Message, DecodeError> {
+    decode_slice(&buf[..])
+}
+
+/**
+ * Reads the header of the message in `buf`, returning a reader positioned
+ * at the start of the body.
+ */
+fn read_header(buf: &[u8]) -> Result<Reader<'_>, DecodeError> {
+    let mut rdr = Reader::new(buf, 0, 0);
+    let head = rdr.read_u32()?;
+    rdr.typ = tags::extract_type(head);
+    rdr.tag = tags::extract_tag(head);
+    Ok(rdr)
+}
+
+fn decode_slice(buf: &[u8]) -> Result<Message, DecodeError> {
+    let mut rdr = read_header(buf)?;
+    let typ = rdr.typ;
+    let tag = rdr.tag;
     if tags::is_fragment(tag) {
-        return Message::Fragment {
-            typ: typ,
-            tag: tag,
-            buf: rest,
-        };
+        return Ok(Message::Fragment {
+            typ,
+            tag,
+            buf: rdr.read_rest().to_vec(),
+        });
     }
     match typ {
         types::TINIT => {
-            let (version, ctx) = init::decode(rest);
-            Message::Tinit {
-                tag: tag,
-                version: version,
+            let (version, ctx) = init::decode(&mut rdr)?;
+            Ok(Message::Tinit {
+                tag,
+                version,
                 headers: ctx,
-            }
+            })
         }
         types::RINIT => {
-            let (version, ctx) = init::decode(rest);
-            Message::Rinit {
-                tag: tag,
-                version: version,
+            let (version, ctx) = init::decode(&mut rdr)?;
+            Ok(Message::Rinit {
+                tag,
+                version,
                 headers: ctx,
-            }
+            })
         }
-        types::TREQ => decode_treq(tag, rest),
-        types::RREQ => decode_rreq(tag, rest),
-        types::TDISPATCH => decode_tdispatch(tag, rest),
-        types::RDISPATCH => decode_rdispatch(tag, rest),
-        types::TDRAIN => Message::Tdrain { tag: tag },
-        types::RDRAIN => Message::Rdrain { tag: tag },
-        types::TPING => Message::Tping { tag: tag },
-        types::RPING => Message::Rping { tag: tag },
+        types::TREQ => decode_treq(&mut rdr),
+        types::RREQ => decode_rreq(&mut rdr),
+        types::TDISPATCH => decode_tdispatch(&mut rdr),
+        types::RDISPATCH => decode_rdispatch(&mut rdr),
+        types::TDRAIN => Ok(Message::Tdrain { tag }),
+        types::RDRAIN => Ok(Message::Rdrain { tag }),
+        types::TPING => Ok(Message::Tping { tag }),
+        types::RPING => Ok(Message::Rping { tag }),
         types::RERR | types::BAD_RERR => {
-            Message::Rerr {
-                tag: tag,
-                error: String::from_utf8(rest).unwrap(),
-            }
+            Ok(Message::Rerr {
+                tag,
+                error: rdr.read_rest_string()?,
+            })
         }
-        types::RDISCARDED => Message::Rdiscarded { tag: tag },
+        types::RDISCARDED => Ok(Message::Rdiscarded { tag }),
         types::TDISCARDED |
-        types::BAD_TDISCARDED => decode_tdiscarded(rest),
-        types::TLEASE => decode_tlease(rest),
-        _ => panic!("unknown message type: {} [tag={}]", typ, tag),
+        types::BAD_TDISCARDED => decode_tdiscarded(&mut rdr),
+        types::TLEASE => decode_tlease(&mut rdr),
+        _ => {
+            Err(DecodeError::UnknownType {
+                typ,
+                tag,
+            })
+        }
+    }
+}
+
+/**
+ * The broadcast contexts of a `MessageRef`, borrowed from the received
+ * message. Keys and values are only sliced out of the message as the
+ * iterator advances.
+ */
+#[derive(Clone, Copy)]
+pub struct Contexts<'a> {
+    rdr: Reader<'a>,
+    n: u16,
+}
+
+impl<'a> Contexts<'a> {
+    /** Skips over the contexts at the reader's position, checking their lengths. */
+    fn skip(rdr: &mut Reader<'a>) -> Result<Contexts<'a>, DecodeError> {
+        let n = rdr.read_u16()?;
+        let contexts = Contexts {
+            rdr: *rdr,
+            n,
+        };
+        for _ in 0..n {
+            let kl = rdr.read_u16()? as usize;
+            rdr.read_bytes(kl)?;
+            let vl = rdr.read_u16()? as usize;
+            rdr.read_bytes(vl)?;
+        }
+        Ok(contexts)
+    }
+
+    /** Returns the number of remaining contexts. */
+    pub fn len(&self) -> usize {
+        self.n as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /** Returns the value of the first context with the given `key`. */
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.clone().find(|&(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn to_vec(self) -> KeyValues {
+        self.map(|(k, v)| (k.to_vec(), v.to_vec())).collect()
+    }
+}
+
+impl<'a> Iterator for Contexts<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<(&'a [u8], &'a [u8])> {
+        if self.n == 0 {
+            return None;
+        }
+        self.n -= 1;
+        // The lengths were checked by `skip`, so these reads cannot fail.
+        let kl = self.rdr.read_u16().ok()? as usize;
+        let k = self.rdr.read_bytes(kl).ok()?;
+        let vl = self.rdr.read_u16().ok()? as usize;
+        let v = self.rdr.read_bytes(vl).ok()?;
+        Some((k, v))
     }
 }
 
-fn encode(msg: Message) -> Vec<u8> {
-    match msg {
-        m @ Message::PreEncodedTping => m.buf(),
-        m => {
-            let tag = m.tag();
-            let typ = m.typ();
-            if tag < tags::MARKER_TAG || (tag & !tags::TAG_MSB) > tags::MAX_TAG {
-                panic!("invalid tag number {}", tag);
+/**
+ * The dtab of a borrowed `Tdispatch`. Entries are validated as UTF-8 as the
+ * iterator advances, so each item may fail.
+ */
+#[derive(Clone, Copy)]
+pub struct DtabRef<'a> {
+    rdr: Reader<'a>,
+    n: u16,
+}
+
+impl<'a> DtabRef<'a> {
+    /** Skips over the dtab at the reader's position, checking its lengths. */
+    fn skip(rdr: &mut Reader<'a>) -> Result<DtabRef<'a>, DecodeError> {
+        let n = rdr.read_u16()?;
+        let dtab = DtabRef {
+            rdr: *rdr,
+            n,
+        };
+        for _ in 0..n {
+            let sl = rdr.read_u16()? as usize;
+            rdr.read_bytes(sl)?;
+            let dl = rdr.read_u16()? as usize;
+            rdr.read_bytes(dl)?;
+        }
+        Ok(dtab)
+    }
+
+    /** Returns the number of remaining dentries. */
+    pub fn len(&self) -> usize {
+        self.n as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    /** Parses the remaining dentries into an owned `Dtab`. */
+    pub fn to_dtab(&self) -> Result<Dtab, DecodeError> {
+        decode_dtab(&mut { self.rdr }, self.n)
+    }
+}
+
+impl<'a> Iterator for DtabRef<'a> {
+    type Item = Result<(&'a str, &'a str), DecodeError>;
+
+    fn next(&mut self) -> Option<Result<(&'a str, &'a str), DecodeError>> {
+        if self.n == 0 {
+            return None;
+        }
+        self.n -= 1;
+        let mut next = || {
+            let sl = self.rdr.read_u16()? as usize;
+            let src = self.rdr.read_str(sl)?;
+            let dl = self.rdr.read_u16()? as usize;
+            let dst = self.rdr.read_str(dl)?;
+            Ok((src, dst))
+        };
+        let entry = next();
+        if entry.is_err() {
+            self.n = 0;
+        }
+        Some(entry)
+    }
+}
+
+/**
+ * A view of a received message which borrows from the buffer it was decoded
+ * from. Dispatch messages are only split into their fields: no payload,
+ * context or dtab entry is copied until asked for, so a proxy can inspect
+ * `dst` and forward `req` without materializing the rest. Other messages are
+ * small and decoded into an owned `Message`.
+ */
+#[derive(Clone)]
+pub enum MessageRef<'a> {
+    Tdispatch {
+        tag: u32,
+        contexts: Contexts<'a>,
+        dst: &'a str,
+        dtab: DtabRef<'a>,
+        req: &'a [u8],
+    },
+    RdispatchOk {
+        tag: u32,
+        contexts: Contexts<'a>,
+        reply: &'a [u8],
+    },
+    RdispatchError {
+        tag: u32,
+        contexts: Contexts<'a>,
+        error: &'a str,
+    },
+    RdispatchNack { tag: u32, contexts: Contexts<'a> },
+    Other(Message),
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn tag(&self) -> u32 {
+        match *self {
+            MessageRef::Tdispatch { tag, .. } |
+            MessageRef::RdispatchOk { tag, .. } |
+            MessageRef::RdispatchError { tag, .. } |
+            MessageRef::RdispatchNack { tag, .. } => tag,
+            MessageRef::Other(ref msg) => msg.tag(),
+        }
+    }
+
+    /** Copies this view into an owned `Message`. */
+    pub fn to_message(&self) -> Result<Message, DecodeError> {
+        match *self {
+            MessageRef::Tdispatch { tag, ref contexts, dst, ref dtab, req } => {
+                let dst = read_dst(dst).map_err(|error| {
+                    DecodeError::BadName {
+                        tag,
+                        // The dtab count follows the destination.
+                        offset: dtab.rdr.pos - 2 - dst.len(),
+                        error,
+                    }
+                })?;
+                Ok(Message::Tdispatch {
+                    tag,
+                    contexts: contexts.to_vec(),
+                    dst,
+                    dtab: dtab.to_dtab()?,
+                    req: req.to_vec(),
+                })
+            }
+            MessageRef::RdispatchOk { tag, ref contexts, reply } => {
+                Ok(Message::RdispatchOk {
+                    tag,
+                    contexts: contexts.to_vec(),
+                    reply: reply.to_vec(),
+                })
+            }
+            MessageRef::RdispatchError { tag, ref contexts, error } => {
+                Ok(Message::RdispatchError {
+                    tag,
+                    contexts: contexts.to_vec(),
+                    error: error.to_string(),
+                })
             }
+            MessageRef::RdispatchNack { tag, ref contexts } => {
+                Ok(Message::RdispatchNack {
+                    tag,
+                    contexts: contexts.to_vec(),
+                })
+            }
+            MessageRef::Other(ref msg) => Ok(msg.clone()),
+        }
+    }
+}
+
+/**
+ * Decodes the message in `buf` (without a size prefix) into a view
+ * borrowing from `buf`. See `MessageRef`.
+ */
+pub fn decode_ref(buf: &[u8]) -> Result<MessageRef<'_>, DecodeError> {
+    let mut rdr = read_header(buf)?;
+    let tag = rdr.tag;
+    if tags::is_fragment(tag) {
+        return decode_slice(buf).map(MessageRef::Other);
+    }
+    match rdr.typ {
+        types::TDISPATCH => {
+            let contexts = Contexts::skip(&mut rdr)?;
+            let ndst = rdr.read_u16()? as usize;
+            let dst = rdr.read_str(ndst)?;
+            let dtab = DtabRef::skip(&mut rdr)?;
+            Ok(MessageRef::Tdispatch {
+                tag,
+                contexts,
+                dst,
+                dtab,
+                req: rdr.read_rest(),
+            })
+        }
+        types::RDISPATCH => {
+            let offset = rdr.pos;
+            let status = rdr.read_u8()?;
+            let contexts = Contexts::skip(&mut rdr)?;
+            match status {
+                0 => {
+                    Ok(MessageRef::RdispatchOk {
+                        tag,
+                        contexts,
+                        reply: rdr.read_rest(),
+                    })
+                }
+                1 => {
+                    let offset = rdr.pos;
+                    let rest = rdr.read_rest();
+                    Ok(MessageRef::RdispatchError {
+                        tag,
+                        contexts,
+                        error: rdr.str(offset, rest)?,
+                    })
+                }
+                2 => {
+                    Ok(MessageRef::RdispatchNack {
+                        tag,
+                        contexts,
+                    })
+                }
+                _ => {
+                    Err(DecodeError::BadStatus {
+                        typ: rdr.typ,
+                        tag,
+                        offset,
+                        status,
+                    })
+                }
+            }
+        }
+        _ => decode_slice(buf).map(MessageRef::Other),
+    }
+}
 
-            let mut head = vec![typ as u8,
-                                (tag >> 16 & 0xff) as u8,
-                                (tag >> 8 & 0xff) as u8,
-                                (tag & 0xff) as u8];
+/**
+ * The size of the type and tag header preceding every message body.
+ */
+pub const HEADER_LEN: usize = 4;
 
-            head.extend_from_slice(&m.buf()[..]);
-            head
+/**
+ * The wire representation of `Message::PreEncodedTping`: a `Tping` with the
+ * reserved ping tag.
+ */
+const PRE_ENCODED_TPING: [u8; HEADER_LEN] = [types::TPING as u8, 0, 0, tags::PING_TAG as u8];
+
+/**
+ * Returns the exact number of bytes `encode_into` writes for `msg`, so that
+ * callers can reserve space up front.
+ */
+pub fn encoded_len(msg: &Message) -> usize {
+    HEADER_LEN + msg.body_len()
+}
+
+/**
+ * Encodes `msg` into `buf` (without a size prefix). Payloads are written
+ * directly into `buf`, so a message is copied exactly once.
+ *
+ * Panics if the message carries a tag outside of the valid tag range, or a
+ * field too long for its 16 bit length prefix.
+ */
+pub fn encode_into<B: BufMut>(msg: &Message, buf: &mut B) {
+    if let Message::PreEncodedTping = *msg {
+        buf.put_slice(&PRE_ENCODED_TPING);
+        return;
+    }
+
+    let tag = msg.tag();
+    let typ = msg.typ();
+    if (tag & !tags::TAG_MSB) > tags::MAX_TAG {
+        panic!("invalid tag number {}", tag);
+    }
+
+    buf.put_u8(typ as u8);
+    buf.put_u8((tag >> 16 & 0xff) as u8);
+    buf.put_u8((tag >> 8 & 0xff) as u8);
+    buf.put_u8((tag & 0xff) as u8);
+    msg.write_body(buf);
+}
+
+/**
+ * Encodes `msg` into its wire representation (without a size prefix).
+ *
+ * Panics if the message carries a tag outside of the valid tag range, or a
+ * field too long for its 16 bit length prefix.
+ */
+pub fn encode(msg: Message) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(encoded_len(&msg));
+    encode_into(&msg, &mut buf);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, decode_ref, encode, encode_into, encoded_len, DecodeError, Message,
+                MessageRef};
+    use super::types;
+
+    #[test]
+    fn test_decode_short() {
+        assert_eq!(decode(vec![0, 0]).err(),
+                   Some(DecodeError::Short {
+                       typ: 0,
+                       tag: 0,
+                       offset: 0,
+                   }));
+        // A Tlease with a truncated duration.
+        assert_eq!(decode(vec![types::TLEASE as u8, 0, 0, 0, 0, 1, 2]).err(),
+                   Some(DecodeError::Short {
+                       typ: types::TLEASE,
+                       tag: 0,
+                       offset: 5,
+                   }));
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        assert_eq!(decode(vec![33, 0, 0, 7]).err(),
+                   Some(DecodeError::UnknownType { typ: 33, tag: 7 }));
+        assert_eq!(decode(vec![types::RREQ as u8, 0, 0, 2, 9]).err(),
+                   Some(DecodeError::BadStatus {
+                       typ: types::RREQ,
+                       tag: 2,
+                       offset: 4,
+                       status: 9,
+                   }));
+        assert_eq!(decode(vec![types::RERR as u8, 0, 0, 3, b'o', b'k', 0xff]).err(),
+                   Some(DecodeError::BadUtf8 {
+                       typ: types::RERR,
+                       tag: 3,
+                       offset: 6,
+                   }));
+
+        // A Tdispatch whose destination is not a path.
+        let buf = vec![types::TDISPATCH as u8, 0, 0, 5, 0, 0, 0, 1, b'a', 0, 0];
+        for err in [decode(buf.clone()).unwrap_err(),
+                    decode_ref(&buf).unwrap().to_message().unwrap_err()] {
+            match err {
+                DecodeError::BadName { tag: 5, offset: 8, ref error } => {
+                    assert_eq!(error.offset(), 0)
+                }
+                err => panic!("unexpected error {:?}", err),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_bad_dentry() {
+        // A Tdispatch to /a whose second dentry /s=>a is invalid.
+        let buf = vec![types::TDISPATCH as u8, 0, 0, 5, 0, 0, 0, 2, b'/', b'a', 0, 2, 0, 2, b'/',
+                       b's', 0, 2, b'/', b'b', 0, 2, b'/', b's', 0, 1, b'a', 9, 9];
+        match decode(buf.clone()).unwrap_err() {
+            DecodeError::BadName { tag: 5, offset: 26, .. } => {}
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        // The borrowed view reads everything but the invalid name.
+        match decode_ref(&buf).unwrap() {
+            MessageRef::Tdispatch { tag, dst, dtab, req, .. } => {
+                assert_eq!(tag, 5);
+                assert_eq!(dst, "/a");
+                assert_eq!(req, &[9, 9]);
+                let dentries = dtab.collect::<Result<Vec<_>, _>>().unwrap();
+                assert_eq!(dentries, vec![("/s", "/b"), ("/s", "a")]);
+            }
+            _ => panic!("expected a Tdispatch"),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_rdispatch() {
+        let buf = encode(Message::RdispatchError {
+            tag: 42,
+            contexts: vec![(b"k".to_vec(), b"v".to_vec())],
+            error: "boom".to_string(),
+        });
+        match decode(buf).unwrap() {
+            Message::RdispatchError { tag, contexts, error } => {
+                assert_eq!(tag, 42);
+                assert_eq!(contexts, vec![(b"k".to_vec(), b"v".to_vec())]);
+                assert_eq!(error, "boom");
+            }
+            _ => panic!("expected RdispatchError"),
+        }
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let msgs = vec![Message::Tdispatch {
+                            tag: 7,
+                            contexts: vec![(b"key".to_vec(), b"value".to_vec())],
+                            dst: "/s/foo".parse().unwrap(),
+                            dtab: "/s=>/$/inet/127.1/8080".parse().unwrap(),
+                            req: vec![1, 2, 3],
+                        },
+                        Message::Tinit {
+                            tag: 1,
+                            version: 1,
+                            headers: vec![(b"mux-framer".to_vec(), vec![0, 0, 1, 0])],
+                        },
+                        Message::Tdiscarded {
+                            which: 9,
+                            why: "timeout".to_string(),
+                        },
+                        Message::Tlease {
+                            unit: 0,
+                            how_long: 1000,
+                        },
+                        Message::RdispatchNack {
+                            tag: 3,
+                            contexts: vec![],
+                        }];
+        for msg in msgs {
+            let mut buf = Vec::new();
+            encode_into(&msg, &mut buf);
+            assert_eq!(buf.len(), encoded_len(&msg));
+            assert_eq!(decode(buf).unwrap(), msg);
         }
     }
+
+    #[test]
+    #[should_panic(expected = "context value of length 65536 exceeds 65535")]
+    fn test_encode_long_context() {
+        let msg = Message::RdispatchOk {
+            tag: 3,
+            contexts: vec![(b"key".to_vec(), vec![0; 65536])],
+            reply: vec![],
+        };
+        encode(msg);
+    }
+
+    #[test]
+    fn test_pre_encoded_tping() {
+        assert_eq!(encode(Message::PreEncodedTping),
+                   encode(Message::Tping { tag: super::tags::PING_TAG }));
+        assert_eq!(Message::PreEncodedTping.typ(), super::types::TPING);
+        assert_eq!(Message::PreEncodedTping.tag(), super::tags::PING_TAG);
+    }
+
+    #[test]
+    fn test_decode_ref() {
+        let msg = Message::Tdispatch {
+            tag: 11,
+            contexts: vec![(b"a".to_vec(), b"1".to_vec()), (b"b".to_vec(), b"22".to_vec())],
+            dst: "/s/foo".parse().unwrap(),
+            dtab: "/s=>/$/inet/127.1/8080".parse().unwrap(),
+            req: vec![5, 6, 7],
+        };
+        let buf = encode(msg.clone());
+        match decode_ref(&buf).unwrap() {
+            MessageRef::Tdispatch { tag, contexts, dst, dtab, req } => {
+                assert_eq!(tag, 11);
+                assert_eq!(contexts.len(), 2);
+                assert_eq!(contexts.get(b"b"), Some(&b"22"[..]));
+                assert_eq!(dst, "/s/foo");
+                assert_eq!(dtab.len(), 1);
+                assert_eq!(req, &[5, 6, 7]);
+            }
+            _ => panic!("expected Tdispatch"),
+        }
+        assert_eq!(decode_ref(&buf).unwrap().to_message().unwrap(), msg);
+
+        let buf = encode(Message::Tping { tag: 4 });
+        match decode_ref(&buf).unwrap() {
+            MessageRef::Other(Message::Tping { tag }) => assert_eq!(tag, 4),
+            _ => panic!("expected Tping"),
+        }
+    }
+
+    #[test]
+    fn test_decode_ref_short_contexts() {
+        // A Tdispatch announcing one context whose key runs past the end.
+        let buf = vec![types::TDISPATCH as u8, 0, 0, 1, 0, 1, 0, 9, b'k'];
+        assert_eq!(decode_ref(&buf).err(),
+                   Some(DecodeError::Short {
+                       typ: types::TDISPATCH,
+                       tag: 1,
+                       offset: 8,
+                   }));
+    }
 }
@@ -1,4 +1,6 @@
-// TODO: temporarily allow dead_code
-#[allow(dead_code)]
-mod message;
-mod mux_framer;
+pub mod handshake;
+pub mod message;
+pub mod mux_framer;
+pub mod session;
+pub mod tag_map;
+pub mod tls;
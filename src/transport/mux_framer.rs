@@ -1,6 +1,13 @@
-use proto::io::{Readiness, Transport};
-use proto::pipeline;
-use std::{io, mem};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{ready, Sink, Stream};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedRead};
+use super::message::{self, tags, DecodeError, Message, HEADER_LEN};
 /**
  * Defines a [[com.twitter.finagle.transport.Transport]] which allows a
  * mux session to be shared between multiple tag streams. The transport splits
@@ -20,198 +27,945 @@ use std::{io, mem};
  * Defines mux framer keys and values exchanged as part of a
  * mux session header during initialization.
  */
-mod header {
-    use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
-    use std::io::Cursor;
-    const KEY_BUF: &'static [u8] = b"mux-framer";
+pub mod header {
+    use byteorder::{ByteOrder, WriteBytesExt, BigEndian};
+    use super::super::handshake::{Feature, HandshakeError};
+    pub const KEY_BUF: &[u8] = b"mux-framer";
 
     /**
      * Returns a header value with the given frame `size` encoded.
      */
-    fn encode_frame_size(size: u32) -> Vec<u8> {
+    pub fn encode_frame_size(size: u32) -> Vec<u8> {
         let mut buf = Vec::new();
         buf.write_u32::<BigEndian>(size).unwrap();
         buf
     }
 
     /**
-     * Extracts frame size from the `buf`.
+     * Extracts frame size from the `buf`, if it holds one.
     */
-    fn decode_frame_size(buf: Vec<u8>) -> u32 {
-        let mut rdr = Cursor::new(buf);
-        rdr.read_u32::<BigEndian>().unwrap()
+    pub fn decode_frame_size(buf: &[u8]) -> Option<u32> {
+        if buf.len() != 4 {
+            return None;
+        }
+        Some(BigEndian::read_u32(buf))
+    }
+
+    /**
+     * Negotiates the fragment size of a session: we advertise the largest
+     * frame we want to handle. Fragmentation is only enabled when both sides
+     * advertise a size, in which case the smaller one is used.
+     */
+    pub struct FrameSize(pub u32);
+
+    impl Feature for FrameSize {
+        fn key(&self) -> &[u8] {
+            KEY_BUF
+        }
+
+        fn offer(&self) -> Option<Vec<u8>> {
+            Some(encode_frame_size(self.0))
+        }
+
+        fn merge(&self, theirs: Option<&[u8]>) -> Result<Option<Vec<u8>>, HandshakeError> {
+            match theirs.map(decode_frame_size) {
+                None => Ok(None),
+                Some(Some(size)) if size > 0 => Ok(Some(encode_frame_size(self.0.min(size)))),
+                Some(_) => Err(HandshakeError::BadHeader(KEY_BUF.to_vec())),
+            }
+        }
     }
 }
 
-pub struct LowLevelTransport<T> {
-    inner: T,
-    read_buffer: Vec<u8>,
-    write_buffer: io::Cursor<Vec<u8>>,
+/**
+ * Splits the encoded message `buf` into frames carrying at most `window` bytes
+ * of the message body each. Every frame is returned with its size prefix and
+ * the header of the message; all but the last one have the tag MSB set to mark
+ * them as fragments.
+ */
+pub fn fragment(buf: &[u8], window: usize) -> Vec<Vec<u8>> {
+    assert!(window > 0, "fragment window must be positive");
+    let typ = buf[0];
+    let tag = BigEndian::read_u32(&buf[..HEADER_LEN]) & 0x00ffffff;
+    let body = &buf[HEADER_LEN..];
+    let n = if body.is_empty() { 1 } else { body.len().div_ceil(window) };
+
+    let mut frames = Vec::with_capacity(n);
+    for i in 0..n {
+        let chunk = &body[i * window..body.len().min((i + 1) * window)];
+        let tag = if i + 1 < n { tags::set_msb(tag) } else { tag };
+        let mut frame = Vec::with_capacity(SIZE_LEN + HEADER_LEN + chunk.len());
+        frame.put_u32((HEADER_LEN + chunk.len()) as u32);
+        frame.put_u8(typ);
+        frame.put_u8((tag >> 16 & 0xff) as u8);
+        frame.put_u8((tag >> 8 & 0xff) as u8);
+        frame.put_u8((tag & 0xff) as u8);
+        frame.put_slice(chunk);
+        frames.push(frame);
+    }
+    frames
+}
+
+/**
+ * Aggregates fragments into complete messages. Fragments are buffered per tag
+ * until the final fragment of a message, the one without the tag MSB, arrives.
+ *
+ * The peer decides how much is buffered, so both the size of a reassembled
+ * message and the bytes of all partial messages together are limited; going
+ * past either fails with `InvalidData`.
+ */
+pub struct Reassembler {
+    pending: HashMap<u32, Vec<u8>>,
+    // The bytes of all partial messages.
+    pending_size: usize,
+    max_message_size: usize,
+    max_pending_size: usize,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler {
+            pending: HashMap::new(),
+            pending_size: 0,
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+            max_pending_size: DEFAULT_MAX_PENDING_SIZE,
+        }
+    }
+
+    /**
+     * Sets the size of the largest message reassembled, without its size
+     * prefix, `DEFAULT_MAX_FRAME_SIZE` unless set.
+     */
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /**
+     * Sets how many bytes of partial messages are buffered across all tags,
+     * `DEFAULT_MAX_PENDING_SIZE` unless set.
+     */
+    pub fn set_max_pending_size(&mut self, max_pending_size: usize) {
+        self.max_pending_size = max_pending_size;
+    }
+
+    /**
+     * Feeds a single frame (without its size prefix) into the reassembler.
+     * Returns the complete message once `buf` is the final fragment, or
+     * directly if `buf` is not a fragment at all.
+     */
+    pub fn push(&mut self, mut buf: Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        if buf.len() < HEADER_LEN {
+            // Too short to carry a tag; let the decoder report it.
+            return Ok(Some(buf));
+        }
+        let tag = BigEndian::read_u32(&buf[..HEADER_LEN]) & 0x00ffffff;
+        let fragment = tags::is_fragment(tag);
+        let tag = tag & !tags::TAG_MSB;
+        let partial = self.pending.get(&tag).map_or(0, Vec::len);
+        if partial == 0 && !fragment {
+            return Ok(Some(buf));
+        }
+
+        // The first fragment carries the header of the complete message.
+        let added = if partial == 0 { buf.len() } else { buf.len() - HEADER_LEN };
+        if partial + added > self.max_message_size {
+            let error = format!("message of at least {} bytes exceeds the maximum of {}",
+                                partial + added,
+                                self.max_message_size);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+
+        if !fragment {
+            let mut msg = self.pending.remove(&tag).expect("partial message");
+            self.pending_size -= msg.len();
+            msg.extend_from_slice(&buf[HEADER_LEN..]);
+            return Ok(Some(msg));
+        }
+
+        if self.pending_size + added > self.max_pending_size {
+            let error = format!("partial messages exceed the maximum of {} bytes",
+                                self.max_pending_size);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, error));
+        }
+        self.pending_size += added;
+        match self.pending.get_mut(&tag) {
+            Some(msg) => msg.extend_from_slice(&buf[HEADER_LEN..]),
+            None => {
+                buf[1] &= !((tags::TAG_MSB >> 16) as u8);
+                self.pending.insert(tag, buf);
+            }
+        }
+        Ok(None)
+    }
+
+    /**
+     * Drops any fragments buffered for `tag`, e.g. because the request was
+     * discarded.
+     */
+    pub fn discard(&mut self, tag: u32) {
+        if let Some(msg) = self.pending.remove(&tag) {
+            self.pending_size -= msg.len();
+        }
+    }
+
+    /** Returns the number of tags with a partially received message. */
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Reassembler {
+        Reassembler::new()
+    }
+}
+
+/**
+ * Returns whether `msg` is a session control message. Control messages are
+ * small, never fragmented and written ahead of any pending application data.
+ */
+pub fn is_control(msg: &Message) -> bool {
+    matches!(*msg,
+             Message::Tping { .. } |
+             Message::PreEncodedTping |
+             Message::Rping { .. } |
+             Message::Tdrain { .. } |
+             Message::Rdrain { .. } |
+             Message::Tdiscarded { .. } |
+             Message::Tlease { .. })
+}
+
+/**
+ * Decides which frame is written next. Control frames always go first; the
+ * fragments of application messages are then taken round-robin across tags, so
+ * a large message only gets its fair share of the connection and cannot starve
+ * messages of other tags. Frames of the same tag are written in the order they
+ * were queued.
+ */
+#[derive(Default)]
+pub struct WriteScheduler {
+    control: VecDeque<Vec<u8>>,
+    queues: HashMap<u32, VecDeque<Vec<u8>>>,
+    // Tags with queued frames, in the order they will be served.
+    ready: VecDeque<u32>,
 }
 
-pub fn transport<T>(inner: T) -> LowLevelTransport<T>
-    where T: io::Read + io::Write + Readiness
+impl WriteScheduler {
+    pub fn new() -> WriteScheduler {
+        WriteScheduler::default()
+    }
+
+    /** Queues a control frame ahead of all application frames. */
+    pub fn push_control(&mut self, frame: Vec<u8>) {
+        self.control.push_back(frame);
+    }
+
+    /** Queues the frames of a message for `tag`. */
+    pub fn push(&mut self, tag: u32, frames: Vec<Vec<u8>>) {
+        if frames.is_empty() {
+            return;
+        }
+        let ready = &mut self.ready;
+        let queue = self.queues.entry(tag).or_insert_with(|| {
+            ready.push_back(tag);
+            VecDeque::new()
+        });
+        queue.extend(frames);
+    }
+
+    /**
+     * Drops the frames queued for `tag`, returning their size. The peer
+     * throws away the fragments it already got when told to discard the tag.
+     */
+    pub fn discard(&mut self, tag: u32) -> usize {
+        match self.queues.remove(&tag) {
+            Some(queue) => {
+                self.ready.retain(|&t| t != tag);
+                queue.iter().map(Vec::len).sum()
+            }
+            None => 0,
+        }
+    }
+
+    /**
+     * Queues the frames of `msg`, as encoded by `codec`, returning their size.
+     * Control messages go ahead of the others; a discard also drops what is
+     * still queued for the tag it discards.
+     */
+    pub fn push_message(&mut self, codec: &MuxCodec, msg: Message) -> usize {
+        if let Message::Tdiscarded { which, .. } = msg {
+            self.discard(which);
+        }
+        let control = is_control(&msg);
+        let tag = msg.tag();
+        let frames = codec.frames(msg);
+        let size = frames.iter().map(Vec::len).sum();
+        if control {
+            self.control.extend(frames);
+        } else {
+            self.push(tag, frames);
+        }
+        size
+    }
+
+    /** Takes the next frame to be written, if any. */
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if let Some(frame) = self.control.pop_front() {
+            return Some(frame);
+        }
+
+        let tag = self.ready.pop_front()?;
+        let (frame, drained) = {
+            let queue = self.queues.get_mut(&tag).expect("ready tag without a queue");
+            (queue.pop_front(), queue.is_empty())
+        };
+        if drained {
+            self.queues.remove(&tag);
+        } else {
+            self.ready.push_back(tag);
+        }
+        frame
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.ready.is_empty()
+    }
+
+    /** Returns the number of control frames waiting to be written. */
+    pub fn control_depth(&self) -> usize {
+        self.control.len()
+    }
+
+    /** Returns the number of frames waiting to be written for `tag`. */
+    pub fn queue_depth(&self, tag: u32) -> usize {
+        self.queues.get(&tag).map_or(0, |q| q.len())
+    }
+
+    /**
+     * Returns the queue depth of every tag with pending frames, in serving
+     * order. Useful to spot head-of-line blocking.
+     */
+    pub fn depths(&self) -> Vec<(u32, usize)> {
+        self.ready.iter().map(|&tag| (tag, self.queue_depth(tag))).collect()
+    }
+}
+
+/**
+ * Every mux message on the wire is preceded by its size, encoded as a 4-byte
+ * big-endian integer.
+ */
+const SIZE_LEN: usize = 4;
+
+/**
+ * Writes a single message with its size prefix to `w`. This is meant for the
+ * session handshake, before the stream is framed with a `MuxCodec`.
+ */
+pub async fn write_message<W>(w: &mut W, msg: &Message) -> io::Result<()>
+    where W: AsyncWrite + Unpin
 {
-    LowLevelTransport {
-        inner: inner,
-        read_buffer: vec![],
-        write_buffer: io::Cursor::new(vec![]),
+    let size = message::encoded_len(msg);
+    let mut bytes = Vec::with_capacity(SIZE_LEN + size);
+    bytes.put_u32(size as u32);
+    message::encode_into(msg, &mut bytes);
+    w.write_all(&bytes).await?;
+    w.flush().await
+}
+
+/**
+ * The largest frame read by default, without its size prefix. The size
+ * prefix comes from the peer, so it has to be checked before anything is
+ * allocated for the frame.
+ */
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/**
+ * The bytes of partial messages buffered across all tags by default, while
+ * waiting for their final fragments.
+ */
+pub const DEFAULT_MAX_PENDING_SIZE: usize = 64 * 1024 * 1024;
+
+fn check_frame_size(size: usize, max_frame_size: usize) -> io::Result<usize> {
+    if size > max_frame_size {
+        let error = format!("frame of {} bytes exceeds the maximum of {}", size, max_frame_size);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, error));
     }
+    Ok(size)
 }
 
-impl<T> Readiness for LowLevelTransport<T>
-    where T: Readiness
+/**
+ * Reads a single message with its size prefix from `r`. Exactly the bytes of
+ * the message are consumed, so the stream can be framed (or upgraded)
+ * afterwards. Messages larger than `DEFAULT_MAX_FRAME_SIZE` are rejected.
+ */
+pub async fn read_message<R>(r: &mut R) -> io::Result<Message>
+    where R: AsyncRead + Unpin
 {
-    fn is_readable(&self) -> bool {
-        self.inner.is_readable()
+    let mut size = [0u8; SIZE_LEN];
+    r.read_exact(&mut size).await?;
+    let size = check_frame_size(BigEndian::read_u32(&size) as usize, DEFAULT_MAX_FRAME_SIZE)?;
+    let mut buf = vec![0u8; size];
+    r.read_exact(&mut buf).await?;
+    message::decode(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/**
+ * A message read from the wire. A malformed message is surfaced as its
+ * `DecodeError` rather than failing the whole stream: the size prefix tells us
+ * where the next message starts.
+ */
+pub type Frame = Result<Message, DecodeError>;
+
+/**
+ * A mux session over which messages are sent and frames received, such as a
+ * `MuxFramed<TcpStream>`.
+ */
+pub trait Transport: Stream<Item = io::Result<Frame>> + Sink<Message, Error = io::Error> + Unpin {
+    /**
+     * Sets the maximum body size of the frames written from now on, as
+     * negotiated by the handshake.
+     */
+    fn set_window(&mut self, window: Option<usize>);
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for Framed<T, MuxCodec> {
+    fn set_window(&mut self, window: Option<usize>) {
+        self.codec_mut().set_window(window);
     }
+}
 
-    fn is_writable(&self) -> bool {
-        let is_writable = self.write_buffer.position() == self.write_buffer.get_ref().len() as u64;
+impl<T: AsyncRead + AsyncWrite + Unpin> Transport for MuxFramed<T> {
+    fn set_window(&mut self, window: Option<usize>) {
+        self.codec_mut().set_window(window);
+    }
+}
 
-        if !is_writable {
-            assert!(!self.inner.is_writable());
+/**
+ * Frames mux messages prefixed with their size, both when reading from and
+ * writing to the wire. Messages larger than the window are split into fragments
+ * when written, and fragments are reassembled per tag when read.
+ */
+pub struct MuxCodec {
+    window: Option<usize>,
+    max_frame_size: usize,
+    reassembler: Reassembler,
+}
+
+impl MuxCodec {
+    pub fn new() -> MuxCodec {
+        MuxCodec {
+            window: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            reassembler: Reassembler::new(),
         }
+    }
+
+    /**
+     * Sets the maximum body size of the frames written to the wire, usually the
+     * window negotiated through the `mux-framer` header. Larger messages are
+     * split into fragments. With no window, messages are always written whole.
+     */
+    pub fn set_window(&mut self, window: Option<usize>) {
+        self.window = window;
+    }
+
+    pub fn window(&self) -> Option<usize> {
+        self.window
+    }
+
+    /**
+     * Sets the size of the largest frame read, without its size prefix,
+     * `DEFAULT_MAX_FRAME_SIZE` unless set. Reading a larger frame fails with
+     * `InvalidData` before any room is made for it, and so does reassembling
+     * a larger message from fragments.
+     */
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+        self.reassembler.set_max_message_size(max_frame_size);
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
 
-        is_writable
+    /**
+     * Sets how many bytes of partial messages are buffered across all tags
+     * while reading, `DEFAULT_MAX_PENDING_SIZE` unless set.
+     */
+    pub fn set_max_pending_size(&mut self, max_pending_size: usize) {
+        self.reassembler.set_max_pending_size(max_pending_size);
     }
 }
 
-/// This defines the chunks written to our transport, i.e. the representation
-/// that the `Service` deals with. In our case, the received and sent frames
-/// are mostly the same (Strings with io::Error as failures), however they
-/// could also be different (for example HttpRequest for In and HttpResponse
-/// for Out).
-pub type Frame = pipeline::Frame<String, io::Error>;
+impl Default for MuxCodec {
+    fn default() -> MuxCodec {
+        MuxCodec::new()
+    }
+}
 
-/// This is a bare-metal implementation of a Transport. We define our frames to be String when
-/// reading from the wire, that is 'In' and also String when writing to the wire.
-impl<T> Transport for LowLevelTransport<T>
-    where T: io::Read + io::Write + Readiness
-{
-    type In = Frame;
-    type Out = Frame;
+impl Decoder for MuxCodec {
+    type Item = Frame;
+    type Error = io::Error;
 
-    /// Read a message from the `Transport`
-    fn read(&mut self) -> io::Result<Option<Frame>> {
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
         loop {
-            // First, we check if our read buffer contains a new line - if that is the case, we
-            // have one new Frame for the Service to consume. We remove the line from the input
-            // buffer and this function will get called by Tokio soon again to see if there are
-            // more frames available.
-            if let Some(n) = self.read_buffer.iter().position(|b| *b == b'\n') {
-                let tail = self.read_buffer.split_off(n + 1);
-                let mut line = mem::replace(&mut self.read_buffer, tail);
-
-                // Remove the new line
-                line.truncate(n);
-
-                return String::from_utf8(line)
-                    // For pipelined protocols, the message must be a tuple
-                    // of the message payload to be sent to the Service and
-                    // Option<Sender<T>> where T is the body chunk type.
-                    //
-                    // To support streaming bodies, the transport could create
-                    // a channel pair, include the receiving end in the message
-                    // payload and provide the sending end to the pipeline
-                    // protocol dispatcher which will then proxy any body chunk
-                    // frame to the Sender.
-                    .map(|s| Some(pipeline::Frame::Message(s)))
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "invalid string"));
+            // We need a size prefix and the full message following it to have a new frame.
+            if src.len() < SIZE_LEN {
+                return Ok(None);
             }
+            let size = BigEndian::read_u32(&src[..SIZE_LEN]) as usize;
+            let size = check_frame_size(size, self.max_frame_size)?;
+            if src.len() < SIZE_LEN + size {
+                src.reserve(SIZE_LEN + size - src.len());
+                return Ok(None);
+            }
+            src.advance(SIZE_LEN);
+            let buf = src.split_to(size).to_vec();
 
-            // There was no full line in the input buffer - let's see if anything is on our
-            // 'inner'.
-            match self.inner.read_to_end(&mut self.read_buffer) {
-                Ok(0) => {
-                    // The other side hang up - this transport is all done.
-                    // TODO(sirver): The use case of this is not entirely clear to me.
-                    return Ok(Some(pipeline::Frame::Done));
-                }
-                Ok(_) => {
-                    // Some data was read. The next round in the loop will try to parse it into a
-                    // line again.
-                }
-                Err(e) => {
-                    // This would block - i.e. there is no data on the socket. We signal Tokio that
-                    // there is right now no full frame available. It will try again the next time
-                    // our source signals readiness to read.
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        return Ok(None);
-                    }
+            trace!("read frame; size={}", size);
+            let buf = match self.reassembler.push(buf)? {
+                Some(buf) => buf,
+                // A fragment of a larger message; keep reading.
+                None => continue,
+            };
 
-                    // Just a regular error - pass upwards for handling.
-                    return Err(e);
-                }
+            let frame = message::decode(buf);
+            if let Ok(Message::Tdiscarded { which, .. }) = frame {
+                self.reassembler.discard(which);
             }
+            return Ok(Some(frame));
         }
     }
+}
 
-    /// Write a message to the `Transport`. This turns the frame we get into a byte string and adds
-    /// a newline. It then immediately gets flushed out to 'inner'.
-    fn write(&mut self, req: Frame) -> io::Result<Option<()>> {
-        match req {
-            pipeline::Frame::Message(req) => {
-                trace!("writing value; val={:?}", req);
-                // Our write buffer can only be non-empty if our 'inner' is not ready for writes.
-                // But since we signal to Tokio that our Transport is not ready when 'inner' is not
-                // ready it should never try to write to us as long as our write buffer is not
-                // empty.
-                if self.write_buffer.position() < self.write_buffer.get_ref().len() as u64 {
-                    return Err(io::Error::new(io::ErrorKind::Other,
-                                              "transport has pending writes"));
-                }
+impl Encoder<Message> for MuxCodec {
+    type Error = io::Error;
+
+    /**
+     * Encodes `msg` straight into `dst`, behind its size. Messages exceeding
+     * the window are written as a sequence of fragments instead, back to
+     * back; a `MuxFramed` interleaves them with the frames of other tags.
+     */
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> io::Result<()> {
+        let size = message::encoded_len(&msg);
+        trace!("writing message; size={}", size);
+
+        if self.fragments(&msg, size) {
+            for frame in self.frames(msg) {
+                dst.extend_from_slice(&frame);
+            }
+        } else {
+            dst.reserve(SIZE_LEN + size);
+            dst.put_u32(size as u32);
+            message::encode_into(&msg, dst);
+        }
+        Ok(())
+    }
+}
+
+impl MuxCodec {
+    /** Returns whether a message of the encoded `size` is split by the window. */
+    fn fragments(&self, msg: &Message, size: usize) -> bool {
+        self.window.is_some_and(|window| !is_control(msg) && size - HEADER_LEN > window)
+    }
+
+    /** Encodes `msg` as the frames written to the wire, with their sizes. */
+    pub fn frames(&self, msg: Message) -> Vec<Vec<u8>> {
+        let size = message::encoded_len(&msg);
+        match self.window {
+            Some(window) if self.fragments(&msg, size) => {
+                let frames = fragment(&message::encode(msg), window);
+                trace!("fragmented message; fragments={}", frames.len());
+                frames
+            }
+            _ => {
+                let mut frame = Vec::with_capacity(SIZE_LEN + size);
+                frame.put_u32(size as u32);
+                message::encode_into(&msg, &mut frame);
+                vec![frame]
+            }
+        }
+    }
+}
+
+/**
+ * The number of bytes queued by a `MuxFramed` beyond which it writes them
+ * out before taking more messages.
+ */
+const BACKPRESSURE_BOUNDARY: usize = 128 * 1024;
+
+/**
+ * A transport framing messages on `T` with a `MuxCodec`, like a
+ * `Framed<T, MuxCodec>`, but writing the frames through a `WriteScheduler`:
+ * control messages go first, and the fragments of the messages queued since
+ * the last flush are interleaved across tags. A large message thus no longer
+ * holds back the messages of other tags sent alongside it.
+ *
+ * A `Tdiscarded` drops whatever is still queued for its tag. The peer then
+ * never gets the whole message, so it won't acknowledge the discard: the
+ * `Rdiscarded` is read from the transport right away instead.
+ */
+pub struct MuxFramed<T> {
+    inner: FramedRead<T, MuxCodec>,
+    scheduler: WriteScheduler,
+    // The frame being written, and how much of it is written.
+    writing: Option<(Vec<u8>, usize)>,
+    // The number of bytes in the scheduler.
+    queued: usize,
+    // The discards of messages which never fully left.
+    acks: VecDeque<Message>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> MuxFramed<T> {
+    pub fn new(io: T) -> MuxFramed<T> {
+        MuxFramed::with_codec(io, MuxCodec::new())
+    }
+
+    pub fn with_codec(io: T, codec: MuxCodec) -> MuxFramed<T> {
+        MuxFramed {
+            inner: FramedRead::new(io, codec),
+            scheduler: WriteScheduler::new(),
+            writing: None,
+            queued: 0,
+            acks: VecDeque::new(),
+        }
+    }
+
+    pub fn codec(&self) -> &MuxCodec {
+        self.inner.decoder()
+    }
+
+    pub fn codec_mut(&mut self) -> &mut MuxCodec {
+        self.inner.decoder_mut()
+    }
+
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref()
+    }
+
+    /** Returns the frames waiting to be written. */
+    pub fn scheduler(&self) -> &WriteScheduler {
+        &self.scheduler
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for MuxFramed<T> {
+    type Item = io::Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(ack) = self.acks.pop_front() {
+            return Poll::Ready(Some(Ok(Ok(ack))));
+        }
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Sink<Message> for MuxFramed<T> {
+    type Error = io::Error;
 
-                let mut bytes = req.into_bytes();
-                bytes.push(b'\n');
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.queued >= BACKPRESSURE_BOUNDARY {
+            return self.poll_flush(cx);
+        }
+        Poll::Ready(Ok(()))
+    }
 
-                self.write_buffer = io::Cursor::new(bytes);
-                self.flush()
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+        trace!("queueing message; size={}", message::encoded_len(&msg));
+        let this = &mut *self;
+        if let Message::Tdiscarded { which, .. } = msg {
+            let dropped = this.scheduler.discard(which);
+            if dropped > 0 {
+                this.queued -= dropped;
+                this.acks.push_back(Message::Rdiscarded { tag: which });
             }
-            _ => unimplemented!(),
         }
+        this.queued += this.scheduler.push_message(this.inner.decoder(), msg);
+        Ok(())
     }
 
-    /// Flush pending writes to the socket. This tries to write as much as possible of the data we
-    /// have in the write buffer to 'inner'. Since this might block - because inner is not ready,
-    /// we have to keep track of what we wrote.
-    fn flush(&mut self) -> io::Result<Option<()>> {
-        trace!("flushing transport");
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
         loop {
-            // Making the borrow checker happy
-            let res = {
-                let buf = {
-                    let pos = self.write_buffer.position() as usize;
-                    let buf = &self.write_buffer.get_ref()[pos..];
-
-                    if buf.is_empty() {
-                        trace!("transport flushed");
-                        return Ok(Some(()));
+            if this.writing.is_none() {
+                match this.scheduler.pop() {
+                    Some(frame) => {
+                        this.queued -= frame.len();
+                        this.writing = Some((frame, 0));
                     }
+                    None => break,
+                }
+            }
+            let (frame, written) = this.writing.as_mut().unwrap();
+            let io = Pin::new(this.inner.get_mut());
+            let n = ready!(io.poll_write(cx, &frame[*written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            *written += n;
+            if *written == frame.len() {
+                this.writing = None;
+            }
+        }
+        Pin::new(this.inner.get_mut()).poll_flush(cx)
+    }
 
-                    trace!("writing; remaining={:?}", buf);
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(self.inner.get_mut()).poll_shutdown(cx)
+    }
+}
 
-                    buf
-                };
+#[cfg(test)]
+mod tests {
+    use std::io;
 
-                self.inner.write(buf)
-            };
+    use byteorder::{BigEndian, ByteOrder};
+    use bytes::BytesMut;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncReadExt;
+    use tokio_util::codec::{Decoder, Encoder};
+    use super::{Frame, MuxCodec, MuxFramed};
+    use super::super::message::{self, DecodeError, Message};
 
-            match res {
-                Ok(mut n) => {
-                    n += self.write_buffer.position() as usize;
-                    self.write_buffer.set_position(n as u64)
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        trace!("transport flush would block");
-                        return Ok(None);
-                    }
+    fn roundtrip(codec: &mut MuxCodec, msgs: Vec<Message>) -> Vec<Frame> {
+        let mut wire = BytesMut::new();
+        for msg in msgs {
+            codec.encode(msg, &mut wire).unwrap();
+        }
+        let mut frames = vec![];
+        while let Some(frame) = codec.decode(&mut wire).unwrap() {
+            frames.push(frame);
+        }
+        assert!(wire.is_empty());
+        frames
+    }
 
-                    trace!("transport flush error; err={:?}", e);
-                    return Err(e);
-                }
+    #[test]
+    fn test_size_prefix() {
+        let mut codec = MuxCodec::new();
+        let mut wire = BytesMut::new();
+        codec.encode(Message::Tping { tag: 5 }, &mut wire).unwrap();
+        assert_eq!(&wire[..], &[0, 0, 0, 4, 65, 0, 0, 5]);
+
+        // Frames are only decoded once complete.
+        let mut partial = BytesMut::from(&wire[..6]);
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+
+        let rerr = Message::Rerr {
+            tag: 6,
+            error: "oops".to_string(),
+        };
+        let frames = roundtrip(&mut codec, vec![Message::Tping { tag: 5 }, rerr.clone()]);
+        assert_eq!(frames, vec![Ok(Message::Tping { tag: 5 }), Ok(rerr)]);
+    }
+
+    #[test]
+    fn test_malformed_frame() {
+        let mut codec = MuxCodec::new();
+        let mut wire = BytesMut::from(&[0, 0, 0, 4, 33, 0, 0, 9][..]);
+        codec.encode(Message::Tping { tag: 5 }, &mut wire).unwrap();
+        // The stream carries on past the malformed message.
+        assert_eq!(codec.decode(&mut wire).unwrap(),
+                   Some(Err(DecodeError::UnknownType { typ: 33, tag: 9 })));
+        assert_eq!(codec.decode(&mut wire).unwrap(), Some(Ok(Message::Tping { tag: 5 })));
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        let mut codec = MuxCodec::new();
+        codec.set_max_frame_size(11);
+        let mut wire = BytesMut::new();
+        codec.encode(Message::rdispatch_ok(3, vec![0; 4]), &mut wire).unwrap();
+        let mut big = BytesMut::from(&[0xff, 0xff, 0xff, 0xff, 254, 0, 0, 3][..]);
+        assert_eq!(codec.decode(&mut big).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // Nothing was reserved for the frame.
+        assert!(big.capacity() < 1024);
+
+        assert!(codec.decode(&mut wire.clone()).unwrap().is_some());
+        codec.set_max_frame_size(10);
+        assert!(codec.decode(&mut wire).is_err());
+    }
+
+    #[test]
+    fn test_reassembly_limits() {
+        // 17 bytes in three fragments, each below the maximum frame size.
+        let msg = Message::rdispatch_ok(3, vec![7; 10]);
+        let mut wire = BytesMut::new();
+        for frame in super::fragment(&message::encode(msg.clone()), 5) {
+            wire.extend_from_slice(&frame);
+        }
+        let mut codec = MuxCodec::new();
+        codec.set_max_frame_size(17);
+        assert_eq!(codec.decode(&mut wire.clone()).unwrap(), Some(Ok(msg)));
+        codec.set_max_frame_size(16);
+        let err = codec.decode(&mut wire).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Partial messages of many tags add up.
+        let mut codec = MuxCodec::new();
+        codec.set_max_pending_size(20);
+        let mut wire = BytesMut::new();
+        for tag in 2..5 {
+            let msg = Message::rdispatch_ok(tag, vec![7; 10]);
+            wire.extend_from_slice(&super::fragment(&message::encode(msg), 5)[0]);
+        }
+        let err = codec.decode(&mut wire).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(codec.reassembler.pending(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let mut wire = &[0, 0, 0, 4, 65, 0, 0, 5][..];
+        assert_eq!(super::read_message(&mut wire).await.unwrap(), Message::Tping { tag: 5 });
+        // The size is checked before the message is read.
+        let mut wire = &[0xff, 0xff, 0xff, 0xff, 65, 0, 0, 5][..];
+        let err = super::read_message(&mut wire).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(wire.len(), 4);
+    }
+
+    #[test]
+    fn test_fragment() {
+        let msg = Message::rdispatch_ok(3, vec![7; 10]);
+        let frames = super::fragment(&message::encode(msg.clone()), 5);
+        assert_eq!(frames.len(), 3);
+        // All but the last fragment have the tag MSB set.
+        assert_eq!(&frames[0][..8], &[0, 0, 0, 9, 254, 0x80, 0, 3]);
+        assert_eq!(&frames[2][..8], &[0, 0, 0, 7, 254, 0, 0, 3]);
+
+        let mut reassembler = super::Reassembler::new();
+        let mut frames = frames.into_iter().map(|mut f| f.split_off(4));
+        assert_eq!(reassembler.push(frames.next().unwrap()).unwrap(), None);
+        // Messages of other tags are passed through untouched.
+        let ping = message::encode(Message::Tping { tag: 9 });
+        assert_eq!(reassembler.push(ping.clone()).unwrap(), Some(ping));
+        assert_eq!(reassembler.push(frames.next().unwrap()).unwrap(), None);
+        assert_eq!(reassembler.pending(), 1);
+        let buf = reassembler.push(frames.next().unwrap()).unwrap().unwrap();
+        assert_eq!(message::decode(buf).unwrap(), msg);
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_fragmented_codec() {
+        let mut codec = MuxCodec::new();
+        codec.set_window(Some(16));
+        let msg = Message::tdispatch(12, "/s/foo".parse().unwrap(), vec![1; 100]);
+        let mut wire = BytesMut::new();
+        codec.encode(msg.clone(), &mut wire).unwrap();
+        // A body of 112 bytes (the payload plus the dispatch fields) makes 7 fragments.
+        assert_eq!(wire.len(), 7 * (4 + 4) + 112);
+
+        let ping = Message::Tping { tag: 1 };
+        let frames = roundtrip(&mut codec, vec![msg.clone(), ping.clone()]);
+        assert_eq!(frames, vec![Ok(msg), Ok(ping)]);
+    }
+
+    #[tokio::test]
+    async fn test_interleaving() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let mut codec = MuxCodec::new();
+        codec.set_window(Some(16));
+        let mut framed = MuxFramed::with_codec(ours, codec);
+        // Both dispatches have a body of 48 bytes, making 3 fragments each.
+        let a = Message::tdispatch(2, "/a".parse().unwrap(), vec![2; 40]);
+        let b = Message::tdispatch(3, "/b".parse().unwrap(), vec![3; 40]);
+        for msg in [a.clone(), b.clone(), Message::Tping { tag: 4 }] {
+            framed.feed(msg).await.unwrap();
+        }
+        assert_eq!(framed.scheduler().depths(), vec![(2, 3), (3, 3)]);
+        assert_eq!(framed.scheduler().control_depth(), 1);
+        framed.flush().await.unwrap();
+        assert!(framed.scheduler().is_empty());
+
+        let mut wire = vec![0; 8 + 2 * (3 * 8 + 48)];
+        theirs.read_exact(&mut wire).await.unwrap();
+        let mut order = vec![];
+        let mut frames = &wire[..];
+        while !frames.is_empty() {
+            let size = BigEndian::read_u32(frames) as usize;
+            order.push(BigEndian::read_u32(&frames[4..]) & 0x7fffff);
+            frames = &frames[4 + size..];
+        }
+        // The ping goes first, then the fragments take turns.
+        assert_eq!(order, vec![4, 2, 3, 2, 3, 2, 3]);
+
+        // The peer reassembles both messages.
+        let mut codec = MuxCodec::new();
+        let mut wire = BytesMut::from(&wire[..]);
+        let mut decoded = vec![];
+        while let Some(frame) = codec.decode(&mut wire).unwrap() {
+            decoded.push(frame);
+        }
+        assert_eq!(decoded, vec![Ok(Message::Tping { tag: 4 }), Ok(a), Ok(b)]);
+    }
+
+    #[test]
+    fn test_discard_jumps_queue() {
+        let mut codec = MuxCodec::new();
+        codec.set_window(Some(16));
+        let mut scheduler = super::WriteScheduler::new();
+        let a = Message::tdispatch(2, "/a".parse().unwrap(), vec![2; 40]);
+        scheduler.push_message(&codec, a);
+        let first = scheduler.pop().unwrap();
+        assert_eq!(BigEndian::read_u32(&first[4..]) & 0x7fffff, 2);
+
+        // The discard goes ahead of the rest of the message, which is dropped.
+        let discard = Message::Tdiscarded {
+            which: 2,
+            why: "timeout".to_string(),
+        };
+        scheduler.push_message(&codec, discard.clone());
+        scheduler.push_message(&codec, Message::Treq { tag: 3, req: vec![] });
+        assert_eq!(scheduler.queue_depth(2), 0);
+        let mut rest = vec![];
+        while let Some(frame) = scheduler.pop() {
+            rest.push(message::decode(frame[4..].to_vec()).unwrap());
+        }
+        assert_eq!(rest, vec![discard, Message::Treq { tag: 3, req: vec![] }]);
+    }
+
+    #[tokio::test]
+    async fn test_discard_unsent() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let mut framed = MuxFramed::new(ours);
+        framed.feed(Message::tdispatch(2, "/a".parse().unwrap(), vec![2; 40])).await.unwrap();
+        let discard = Message::Tdiscarded {
+            which: 2,
+            why: "timeout".to_string(),
+        };
+        framed.feed(discard.clone()).await.unwrap();
+        framed.flush().await.unwrap();
+
+        // The request never left, so the discard is acknowledged locally.
+        let ack = framed.next().await.unwrap().unwrap();
+        assert_eq!(ack, Ok(Message::Rdiscarded { tag: 2 }));
+        let mut wire = vec![0; 4 + message::encoded_len(&discard)];
+        theirs.read_exact(&mut wire).await.unwrap();
+        assert_eq!(message::decode(wire.split_off(4)).unwrap(), discard);
+    }
+
+    #[test]
+    fn test_write_scheduler() {
+        let mut scheduler = super::WriteScheduler::new();
+        scheduler.push(2, vec![vec![21], vec![22], vec![23]]);
+        scheduler.push(3, vec![vec![31]]);
+        scheduler.push(2, vec![vec![24]]);
+        scheduler.push_control(vec![1]);
+        assert_eq!(scheduler.depths(), vec![(2, 4), (3, 1)]);
+        assert_eq!(scheduler.control_depth(), 1);
+
+        let mut order = vec![];
+        while let Some(frame) = scheduler.pop() {
+            order.push(frame[0]);
+            if frame[0] == 21 {
+                // Control frames jump ahead of pending fragments.
+                scheduler.push_control(vec![0]);
             }
         }
+        assert_eq!(order, vec![1, 21, 0, 31, 22, 23, 24]);
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.queue_depth(2), 0);
     }
 }
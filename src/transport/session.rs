@@ -0,0 +1,715 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use crate::rpc::{Error, Request, Response};
+use crate::Path;
+use super::handshake::{ClientHandshake, HandshakeError, Negotiated, Registry, ServerHandshake};
+use super::message::{tags, Message};
+use super::mux_framer::{Frame, MuxCodec, WriteScheduler};
+use super::tag_map::TagMap;
+
+/**
+ * The tag of the `Tdrain` messages we send.
+ */
+pub const DRAIN_TAG: u32 = 1;
+
+/**
+ * The unit of `Tlease` durations: milliseconds is the only one defined.
+ */
+pub const LEASE_MILLIS: u8 = 0;
+
+/**
+ * Something that happened on a session, for its owner to act upon.
+ */
+#[derive(Debug)]
+pub enum Event<T> {
+    /** The handshake completed. */
+    Established(Negotiated),
+    /** A request arrived, to be answered with `Session::respond`. */
+    Request { tag: u32, req: Request },
+    /**
+     * The client discarded the request with the given tag; any response to
+     * it is dropped.
+     */
+    Discarded { tag: u32, why: String },
+    /**
+     * A request of ours is done and its tag was freed. A discarded request
+     * completes with its late reply or `Error::Discarded`.
+     */
+    Response {
+        tag: u32,
+        data: T,
+        rep: Result<Response, Error>,
+    },
+    /** The server asked us to drain: the session takes no new requests. */
+    Drain,
+    /**
+     * Our drain was acknowledged and every request received before it has
+     * been answered; the session can be closed.
+     */
+    Drained,
+    /** The server granted us a lease of the given duration. */
+    Lease(Duration),
+    /** The peer answered our ping. */
+    Pong,
+}
+
+/**
+ * Distinguishes requests received as `Tdispatch` from the deprecated `Treq`,
+ * as each is answered with its own reply type.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Dispatch,
+    Req,
+}
+
+fn reply(kind: Kind, tag: u32, rep: Result<Response, Error>) -> Message {
+    match (kind, rep) {
+        (Kind::Dispatch, Ok(rep)) => {
+            Message::RdispatchOk {
+                tag,
+                contexts: rep.contexts,
+                reply: rep.body,
+            }
+        }
+        (Kind::Dispatch, Err(Error::Nack)) => {
+            Message::RdispatchNack {
+                tag,
+                contexts: Vec::new(),
+            }
+        }
+        (Kind::Dispatch, Err(Error::Application(error))) => Message::rdispatch_error(tag, error),
+        (Kind::Dispatch, Err(e)) => Message::rdispatch_error(tag, e.to_string()),
+        (Kind::Req, Ok(rep)) => Message::RreqOk { tag, reply: rep.body },
+        (Kind::Req, Err(Error::Nack)) => Message::RreqNack { tag },
+        (Kind::Req, Err(Error::Application(error))) => Message::RreqError { tag, error },
+        (Kind::Req, Err(e)) => {
+            Message::RreqError {
+                tag,
+                error: e.to_string(),
+            }
+        }
+    }
+}
+
+enum State {
+    ClientHandshake(ClientHandshake),
+    ServerHandshake(ServerHandshake),
+    Open,
+    Closed,
+}
+
+struct Outstanding<T> {
+    data: T,
+    discarded: bool,
+}
+
+/**
+ * The protocol logic of a mux session, free of any I/O: the owner feeds it
+ * what it reads (as bytes with `receive`, or as frames with `handle`),
+ * drives it through its methods, and writes what it emits (as bytes with
+ * `transmit`, or as messages with `poll_message`). Whatever needs the
+ * owner's attention is reported through `poll_event`.
+ *
+ * A client session keeps the given `T` alongside each of its requests and
+ * hands it back with the response; a server session reports requests as
+ * events and answers them with `respond`. Either side answers pings.
+ */
+pub struct Session<T = ()> {
+    server: bool,
+    state: State,
+    codec: MuxCodec,
+    read_buf: BytesMut,
+    // Messages written while the handshake is in progress.
+    held: Vec<Message>,
+    outbound: VecDeque<Message>,
+    events: VecDeque<Event<T>>,
+    // Our requests, by tag.
+    outstanding: TagMap<Outstanding<T>>,
+    // The requests of the peer being served, by tag.
+    in_flight: HashMap<u32, Kind>,
+    // Sent or received a Tdrain, depending on the side.
+    draining: bool,
+    drain_acked: bool,
+    drained: bool,
+}
+
+impl<T> Session<T> {
+    fn new(server: bool, state: State) -> Session<T> {
+        Session {
+            server,
+            state,
+            codec: MuxCodec::new(),
+            read_buf: BytesMut::new(),
+            held: Vec::new(),
+            outbound: VecDeque::new(),
+            events: VecDeque::new(),
+            outstanding: TagMap::new(),
+            in_flight: HashMap::new(),
+            draining: false,
+            drain_acked: false,
+            drained: false,
+        }
+    }
+
+    /** Creates the client side of a session established without handshake. */
+    pub fn client() -> Session<T> {
+        Session::new(false, State::Open)
+    }
+
+    /** Creates the server side of a session established without handshake. */
+    pub fn server() -> Session<T> {
+        Session::new(true, State::Open)
+    }
+
+    /**
+     * Creates the client side of a session which opens with a `Tinit`
+     * offering the features in `registry`. Requests made before the server
+     * answers are held back until then.
+     */
+    pub fn connect(registry: Registry) -> Session<T> {
+        let mut handshake = ClientHandshake::new(registry);
+        let tinit = handshake.tinit();
+        let mut session = Session::new(false, State::ClientHandshake(handshake));
+        session.outbound.push_back(tinit);
+        session
+    }
+
+    /**
+     * Creates the server side of a session which answers the client's
+     * `Tinit` with the features in `registry`.
+     */
+    pub fn accept(registry: Registry) -> Session<T> {
+        Session::new(true, State::ServerHandshake(ServerHandshake::new(registry)))
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(self.state, State::Closed)
+    }
+
+    /** Returns whether the session drains, having sent or received a `Tdrain`. */
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /** Returns whether `request` may be called. */
+    pub fn accepts_requests(&self) -> bool {
+        !self.server && !self.draining && self.is_open()
+    }
+
+    /** Returns the number of our requests waiting for their response. */
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    /** Returns the number of requests of the peer not answered yet. */
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /**
+     * Feeds bytes read from the wire. Fails, closing the session, if the
+     * handshake fails. A frame larger than the codec's maximum frame size
+     * also closes the session, as the rest of the stream can't be framed.
+     */
+    pub fn receive(&mut self, bytes: &[u8]) -> Result<(), HandshakeError> {
+        self.read_buf.extend_from_slice(bytes);
+        // Malformed messages are yielded as frames; only the framing fails.
+        loop {
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(frame)) => self.handle(frame)?,
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    warn!("closing session: {}", e);
+                    self.read_buf.clear();
+                    self.state = State::Closed;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /**
+     * Writes the messages emitted so far to `dst`, fragmented according to
+     * the negotiated window. Control messages go first, and the fragments of
+     * different tags are interleaved.
+     */
+    pub fn transmit(&mut self, dst: &mut BytesMut) {
+        let mut scheduler = WriteScheduler::new();
+        while let Some(msg) = self.outbound.pop_front() {
+            scheduler.push_message(&self.codec, msg);
+        }
+        while let Some(frame) = scheduler.pop() {
+            dst.extend_from_slice(&frame);
+        }
+    }
+
+    /**
+     * Takes the next message to write. Meant for owners which frame messages
+     * themselves, e.g. with a `MuxCodec`.
+     */
+    pub fn poll_message(&mut self) -> Option<Message> {
+        self.outbound.pop_front()
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event<T>> {
+        self.events.pop_front()
+    }
+
+    /**
+     * Handles a frame read from the wire. Fails, closing the session, if the
+     * handshake fails, which includes a malformed answer to our `Tinit`.
+     */
+    pub fn handle(&mut self, frame: Frame) -> Result<(), HandshakeError> {
+        let msg = match frame {
+            Ok(msg) => msg,
+            Err(e) => {
+                if let State::ClientHandshake(_) = self.state {
+                    // No Rinit is coming: the session can't be established.
+                    return Err(self.fail(HandshakeError::Malformed(e)));
+                }
+                if self.server {
+                    // Reject messages we can't decode, such as unknown types, with an Rerr for
+                    // their tag instead of failing the whole session.
+                    self.write(Message::rerr(e.tag(), e.to_string()));
+                } else {
+                    warn!("dropping malformed message: {}", e);
+                }
+                return Ok(());
+            }
+        };
+
+        match mem::replace(&mut self.state, State::Open) {
+            State::Open => {}
+            State::Closed => {
+                self.state = State::Closed;
+                debug!("ignoring message on closed session [type={}, tag={}]",
+                       msg.typ(),
+                       msg.tag());
+                return Ok(());
+            }
+            State::ClientHandshake(mut handshake) => {
+                let negotiated = handshake.receive(msg).map_err(|e| self.fail(e))?;
+                self.establish(negotiated);
+                return Ok(());
+            }
+            State::ServerHandshake(mut handshake) => {
+                match handshake.receive(&msg) {
+                    Ok((negotiated, Some(rinit))) => {
+                        self.outbound.push_back(rinit);
+                        self.establish(negotiated);
+                        return Ok(());
+                    }
+                    // A legacy client; its first message is a regular one.
+                    Ok((negotiated, None)) => self.establish(negotiated),
+                    Err(e) => {
+                        self.outbound.push_back(e.to_rerr(msg.tag()));
+                        return Err(self.fail(e));
+                    }
+                }
+            }
+        }
+
+        if self.server {
+            self.handle_server(msg);
+        } else {
+            self.handle_client(msg);
+        }
+        Ok(())
+    }
+
+    fn fail(&mut self, e: HandshakeError) -> HandshakeError {
+        debug!("session handshake failed: {}", e);
+        self.state = State::Closed;
+        e
+    }
+
+    fn establish(&mut self, negotiated: Negotiated) {
+        self.codec.set_window(negotiated.window.map(|w| w as usize));
+        self.outbound.extend(self.held.drain(..));
+        self.events.push_back(Event::Established(negotiated));
+    }
+
+    fn write(&mut self, msg: Message) {
+        match self.state {
+            State::ClientHandshake(_) |
+            State::ServerHandshake(_) => self.held.push(msg),
+            State::Open => self.outbound.push_back(msg),
+            State::Closed => {}
+        }
+    }
+
+    fn handle_client(&mut self, msg: Message) {
+        match msg {
+            Message::RdispatchOk { tag, contexts, reply } => {
+                self.complete(tag, Ok(Response { contexts, body: reply }))
+            }
+            Message::RdispatchError { tag, error, .. } |
+            Message::RreqError { tag, error } => self.complete(tag, Err(Error::Application(error))),
+            Message::RdispatchNack { tag, .. } |
+            Message::RreqNack { tag } => self.complete(tag, Err(Error::Nack)),
+            Message::RreqOk { tag, reply } => self.complete(tag, Ok(Response::new(reply))),
+            Message::Rerr { tag, error } => self.complete(tag, Err(Error::Rerr(error))),
+            Message::Rdiscarded { tag } => {
+                if self.outstanding.get(tag).is_some_and(|o| o.discarded) {
+                    self.complete(tag, Err(Error::Discarded));
+                }
+            }
+            Message::Tdrain { tag } => {
+                debug!("session draining at the server's request");
+                self.draining = true;
+                self.write(Message::Rdrain { tag });
+                self.events.push_back(Event::Drain);
+            }
+            Message::Tlease { unit: LEASE_MILLIS, how_long } => {
+                self.events.push_back(Event::Lease(Duration::from_millis(how_long)))
+            }
+            Message::Tlease { unit, .. } => debug!("ignoring lease of unknown unit {}", unit),
+            msg => self.handle_control(msg),
+        }
+    }
+
+    fn handle_server(&mut self, msg: Message) {
+        match msg {
+            Message::Tdispatch { tag, contexts, dst, dtab, req } => {
+                let req = Request {
+                    dst,
+                    dtab,
+                    contexts,
+                    body: req,
+                };
+                self.start(tag, Kind::Dispatch, req)
+            }
+            Message::Treq { tag, req } => {
+                self.start(tag, Kind::Req, Request::new(Path::empty(), req))
+            }
+            Message::Tdiscarded { which, why } => {
+                if self.in_flight.remove(&which).is_some() {
+                    debug!("discarded request; tag={}, why={}", which, why);
+                    self.write(Message::Rdiscarded { tag: which });
+                    self.events.push_back(Event::Discarded { tag: which, why });
+                    self.check_drained();
+                }
+            }
+            Message::Rdrain { .. } if self.draining => {
+                self.drain_acked = true;
+                self.check_drained();
+            }
+            msg => self.handle_control(msg),
+        }
+    }
+
+    /** Handles the messages common to both sides. */
+    fn handle_control(&mut self, msg: Message) {
+        match msg {
+            Message::Tping { tag } => self.write(Message::Rping { tag }),
+            Message::Rping { tag: tags::PING_TAG } => self.events.push_back(Event::Pong),
+            // Requests of a kind this side does not serve.
+            msg if msg.typ() > 0 => {
+                let error = format!("unexpected message type {}", msg.typ());
+                self.write(Message::rerr(msg.tag(), error));
+            }
+            msg => debug!("ignoring message [type={}, tag={}]", msg.typ(), msg.tag()),
+        }
+    }
+
+    /** Sends our ping, answered with an `Event::Pong`. */
+    pub fn ping(&mut self) {
+        self.write(Message::PreEncodedTping);
+    }
+
+    /**
+     * Dispatches `req` as a client, keeping `data` until its response.
+     * Returns the tag of the request, or hands both back if the session does
+     * not accept requests or has all its tags in use.
+     */
+    pub fn request(&mut self, req: Request, data: T) -> Result<u32, (Request, T)> {
+        if !self.accepts_requests() {
+            return Err((req, data));
+        }
+        let tag = match self.outstanding.map(Outstanding { data, discarded: false }) {
+            Ok(tag) => tag,
+            Err(outstanding) => return Err((req, outstanding.data)),
+        };
+        self.write(Message::Tdispatch {
+            tag,
+            contexts: req.contexts,
+            dst: req.dst,
+            dtab: req.dtab,
+            req: req.body,
+        });
+        Ok(tag)
+    }
+
+    /**
+     * Discards our request with the given tag, telling the server `why`. The
+     * tag is only freed once the server acknowledges, or its reply arrives
+     * after all. Returns whether there was such a request to discard.
+     */
+    pub fn discard(&mut self, tag: u32, why: String) -> bool {
+        match self.outstanding.get_mut(tag) {
+            Some(outstanding) if !outstanding.discarded => outstanding.discarded = true,
+            _ => return false,
+        }
+        self.write(Message::Tdiscarded { which: tag, why });
+        true
+    }
+
+    fn complete(&mut self, tag: u32, rep: Result<Response, Error>) {
+        match self.outstanding.unmap(tag) {
+            Some(outstanding) => {
+                self.events.push_back(Event::Response {
+                    tag,
+                    data: outstanding.data,
+                    rep,
+                })
+            }
+            None => debug!("reply for unknown tag {}", tag),
+        }
+    }
+
+    fn start(&mut self, tag: u32, kind: Kind, req: Request) {
+        if self.in_flight.contains_key(&tag) {
+            self.write(Message::rerr(tag, format!("duplicate tag {}", tag)));
+        } else if self.draining {
+            // The client may not have seen our Tdrain yet; it can retry elsewhere.
+            self.write(reply(kind, tag, Err(Error::Nack)));
+        } else {
+            self.in_flight.insert(tag, kind);
+            self.events.push_back(Event::Request { tag, req });
+        }
+    }
+
+    /**
+     * Answers the request of the peer with the given tag. Responses to
+     * discarded requests are dropped.
+     */
+    pub fn respond(&mut self, tag: u32, rep: Result<Response, Error>) {
+        match self.in_flight.remove(&tag) {
+            Some(kind) => {
+                self.write(reply(kind, tag, rep));
+                self.check_drained();
+            }
+            None => debug!("dropping response for tag {}", tag),
+        }
+    }
+
+    /**
+     * Asks the client to stop sending requests. Requests arriving from then
+     * on are nacked; `Event::Drained` reports when the client acknowledged
+     * and all earlier requests were answered.
+     */
+    pub fn drain(&mut self) {
+        if !self.draining {
+            self.draining = true;
+            self.write(Message::Tdrain { tag: DRAIN_TAG });
+        }
+    }
+
+    fn check_drained(&mut self) {
+        if self.drain_acked && self.in_flight.is_empty() && !self.drained {
+            self.drained = true;
+            self.events.push_back(Event::Drained);
+        }
+    }
+
+    /** Grants the client a lease of `how_long`. */
+    pub fn lease(&mut self, how_long: Duration) {
+        self.write(Message::Tlease {
+            unit: LEASE_MILLIS,
+            how_long: how_long.as_millis() as u64,
+        });
+    }
+
+    /**
+     * Closes the session, returning the data of our requests still waiting
+     * for their response.
+     */
+    pub fn close(&mut self) -> Vec<T> {
+        self.state = State::Closed;
+        self.outbound.clear();
+        self.in_flight.clear();
+        self.outstanding.drain().into_iter().map(|(_, outstanding)| outstanding.data).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::{BufMut, BytesMut};
+    use tokio_util::codec::Encoder;
+
+    use super::{Event, Session};
+    use crate::rpc::{Error, Request, Response};
+    use crate::transport::handshake::{HandshakeError, Registry};
+    use crate::transport::message::{DecodeError, Message};
+    use crate::transport::mux_framer::header::FrameSize;
+    use crate::transport::mux_framer::{MuxCodec, DEFAULT_MAX_FRAME_SIZE};
+
+    /** Moves everything written by `from` over to `to`. */
+    fn pipe<A, B>(from: &mut Session<A>, to: &mut Session<B>) {
+        let mut wire = BytesMut::new();
+        from.transmit(&mut wire);
+        to.receive(&wire).unwrap();
+    }
+
+    fn events<T>(session: &mut Session<T>) -> Vec<Event<T>> {
+        let mut events = vec![];
+        while let Some(event) = session.poll_event() {
+            events.push(event);
+        }
+        events
+    }
+
+    fn pair() -> (Session<&'static str>, Session) {
+        let mut registry = Registry::new();
+        registry.register(FrameSize(8));
+        let mut client = Session::connect(registry);
+        let mut registry = Registry::new();
+        registry.register(FrameSize(16));
+        let mut server = Session::accept(registry);
+
+        // Requests made during the handshake are held back.
+        client.request(Request::new("/a".parse().unwrap(), vec![1; 20]), "a").unwrap();
+        pipe(&mut client, &mut server);
+        pipe(&mut server, &mut client);
+        match &events(&mut client)[..] {
+            [Event::Established(negotiated)] => assert_eq!(negotiated.window, Some(8)),
+            events => panic!("unexpected events {:?}", events),
+        }
+        pipe(&mut client, &mut server);
+        match &events(&mut server)[..] {
+            [Event::Established(_), Event::Request { tag: 2, req }] => {
+                assert_eq!(req.body, vec![1; 20])
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        (client, server)
+    }
+
+    #[test]
+    fn test_request_response() {
+        let (mut client, mut server) = pair();
+        let tag = client.request(Request::new("/b".parse().unwrap(), vec![2]), "b").unwrap();
+        pipe(&mut client, &mut server);
+        assert_eq!(server.in_flight(), 2);
+        server.respond(tag, Err(Error::Nack));
+        server.respond(2, Ok(Response::new(vec![3; 30])));
+        pipe(&mut server, &mut client);
+
+        match &events(&mut client)[..] {
+            [Event::Response { data: "b", rep: Err(Error::Nack), .. },
+             Event::Response { tag: 2, data: "a", rep: Ok(rep) }] => {
+                assert_eq!(rep.body, vec![3; 30])
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert_eq!(client.outstanding(), 0);
+        assert_eq!(server.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_ping() {
+        let (mut client, mut server) = pair();
+        client.ping();
+        server.ping();
+        pipe(&mut client, &mut server);
+        pipe(&mut server, &mut client);
+        pipe(&mut client, &mut server);
+        assert!(matches!(&events(&mut client)[..], [Event::Pong]));
+        assert!(matches!(&events(&mut server)[..], [Event::Pong]));
+    }
+
+    #[test]
+    fn test_discard() {
+        let (mut client, mut server) = pair();
+        assert!(client.discard(2, "timeout".to_string()));
+        assert!(!client.discard(2, "timeout".to_string()));
+        pipe(&mut client, &mut server);
+        match &events(&mut server)[..] {
+            [Event::Discarded { tag: 2, why }] => assert_eq!(why, "timeout"),
+            events => panic!("unexpected events {:?}", events),
+        }
+        // The handler finishing after all is not answered.
+        server.respond(2, Ok(Response::new(vec![])));
+        assert_eq!(server.poll_message(), Some(Message::Rdiscarded { tag: 2 }));
+        assert_eq!(server.poll_message(), None);
+
+        // The tag stays in use until acknowledged.
+        assert_eq!(client.outstanding(), 1);
+        let mut wire = BytesMut::new();
+        MuxCodec::new().encode(Message::Rdiscarded { tag: 2 }, &mut wire).unwrap();
+        client.receive(&wire).unwrap();
+        assert!(matches!(&events(&mut client)[..],
+                         [Event::Response { tag: 2, rep: Err(Error::Discarded), .. }]));
+        assert_eq!(client.outstanding(), 0);
+    }
+
+    #[test]
+    fn test_drain() {
+        let (mut client, mut server) = pair();
+        server.drain();
+        pipe(&mut server, &mut client);
+        assert!(matches!(&events(&mut client)[..], [Event::Drain]));
+        assert!(!client.accepts_requests());
+        assert!(client.request(Request::new("/c".parse().unwrap(), vec![]), "c").is_err());
+        pipe(&mut client, &mut server);
+        // The outstanding request is still answered before the session is drained.
+        assert!(events(&mut server).is_empty());
+        server.respond(2, Ok(Response::new(vec![])));
+        assert!(matches!(&events(&mut server)[..], [Event::Drained]));
+        pipe(&mut server, &mut client);
+        assert!(matches!(&events(&mut client)[..], [Event::Response { tag: 2, rep: Ok(_), .. }]));
+    }
+
+    #[test]
+    fn test_lease() {
+        let (mut client, mut server) = pair();
+        server.lease(Duration::from_secs(2));
+        pipe(&mut server, &mut client);
+        match &events(&mut client)[..] {
+            [Event::Lease(how_long)] => assert_eq!(*how_long, Duration::from_secs(2)),
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn test_frame_too_large() {
+        let (mut client, _server) = pair();
+        let mut wire = BytesMut::new();
+        wire.put_u32(DEFAULT_MAX_FRAME_SIZE as u32 + 1);
+        client.receive(&wire).unwrap();
+        assert!(!client.is_open());
+    }
+
+    #[test]
+    fn test_malformed_rinit() {
+        let mut client = Session::connect(Registry::new());
+        client.request(Request::new("/a".parse().unwrap(), vec![]), "a").unwrap();
+        // A message of an unknown type instead of the Rinit.
+        let err = client.receive(&[0, 0, 0, 4, 33, 0, 0, 1]).unwrap_err();
+        assert_eq!(err, HandshakeError::Malformed(DecodeError::UnknownType { typ: 33, tag: 1 }));
+        assert!(!client.is_open());
+        assert_eq!(client.close(), vec!["a"]);
+    }
+
+    #[test]
+    fn test_legacy_client() {
+        let mut client = Session::<()>::client();
+        let mut server = Session::<()>::accept(Registry::new());
+        client.request(Request::new("/a".parse().unwrap(), vec![]), ()).unwrap();
+        pipe(&mut client, &mut server);
+        match &events(&mut server)[..] {
+            [Event::Established(negotiated), Event::Request { tag: 2, .. }] => {
+                assert!(negotiated.is_legacy())
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::cmp::Reverse;
+use std::collections::hash_map;
+use std::collections::{BinaryHeap, HashMap};
+
+use super::message::tags::{MAX_TAG, MIN_TAG};
+
+/**
+ * Allocates tags for outstanding requests and maps each of them to the
+ * state of its request, so that replies can be routed back to their caller.
+ *
+ * Tags are handed out from `MIN_TAG..=MAX_TAG`, which leaves out the
+ * reserved marker and ping tags. Freed tags are recycled lowest first, and
+ * when every tag is in flight `map` refuses to allocate instead of wrapping
+ * around onto a tag which is still in use.
+ */
+pub struct TagMap<T> {
+    // The lowest tag which has never been handed out.
+    next: u32,
+    max: u32,
+    free: BinaryHeap<Reverse<u32>>,
+    inflight: HashMap<u32, T>,
+}
+
+impl<T> TagMap<T> {
+    /** Creates a map over the full tag space. */
+    pub fn new() -> TagMap<T> {
+        TagMap::with_range(MIN_TAG, MAX_TAG)
+    }
+
+    /**
+     * Creates a map handing out tags from `min..=max` only, e.g. to bound
+     * the number of outstanding requests of a session.
+     */
+    pub fn with_range(min: u32, max: u32) -> TagMap<T> {
+        assert!(MIN_TAG <= min && min <= max && max <= MAX_TAG,
+                "invalid tag range {}..={}",
+                min,
+                max);
+        TagMap {
+            next: min,
+            max,
+            free: BinaryHeap::new(),
+            inflight: HashMap::new(),
+        }
+    }
+
+    /**
+     * Allocates a tag for `value`. When all tags are in flight, `value` is
+     * handed back as `Err` so the caller can apply back pressure until a tag
+     * is freed.
+     */
+    pub fn map(&mut self, value: T) -> Result<u32, T> {
+        let tag = match self.free.pop() {
+            Some(Reverse(tag)) => tag,
+            None if self.next <= self.max => {
+                self.next += 1;
+                self.next - 1
+            }
+            None => return Err(value),
+        };
+        self.inflight.insert(tag, value);
+        Ok(tag)
+    }
+
+    /** Frees `tag`, returning the value it was mapped to. */
+    pub fn unmap(&mut self, tag: u32) -> Option<T> {
+        let value = self.inflight.remove(&tag);
+        if value.is_some() {
+            self.free.push(Reverse(tag));
+        }
+        value
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&T> {
+        self.inflight.get(&tag)
+    }
+
+    pub fn get_mut(&mut self, tag: u32) -> Option<&mut T> {
+        self.inflight.get_mut(&tag)
+    }
+
+    /** Returns the number of tags in flight. */
+    pub fn len(&self) -> usize {
+        self.inflight.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inflight.is_empty()
+    }
+
+    /** Returns whether every tag is in flight. */
+    pub fn is_exhausted(&self) -> bool {
+        self.free.is_empty() && self.next > self.max
+    }
+
+    /** Iterates over the tags in flight and their values, in no particular order. */
+    pub fn iter(&self) -> hash_map::Iter<'_, u32, T> {
+        self.inflight.iter()
+    }
+
+    /**
+     * Frees every tag, returning the values they were mapped to, e.g. to
+     * fail all outstanding requests when the session closes.
+     */
+    pub fn drain(&mut self) -> Vec<(u32, T)> {
+        let drained: Vec<(u32, T)> = self.inflight.drain().collect();
+        for &(tag, _) in &drained {
+            self.free.push(Reverse(tag));
+        }
+        drained
+    }
+}
+
+impl<T> Default for TagMap<T> {
+    fn default() -> TagMap<T> {
+        TagMap::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TagMap;
+    use super::super::message::tags::{MARKER_TAG, MIN_TAG, PING_TAG};
+
+    #[test]
+    fn test_reserved_tags() {
+        let mut tags = TagMap::new();
+        let tag = tags.map("a").unwrap();
+        assert_eq!(tag, MIN_TAG);
+        assert!(tag != MARKER_TAG && tag != PING_TAG);
+        assert_eq!(tags.get(tag), Some(&"a"));
+    }
+
+    #[test]
+    fn test_recycle_lowest_first() {
+        let mut tags = TagMap::new();
+        let a = tags.map(1).unwrap();
+        let b = tags.map(2).unwrap();
+        let c = tags.map(3).unwrap();
+        assert_eq!(tags.unmap(c), Some(3));
+        assert_eq!(tags.unmap(a), Some(1));
+        assert_eq!(tags.unmap(a), None);
+        assert_eq!(tags.map(4), Ok(a));
+        assert_eq!(tags.map(5), Ok(c));
+        assert_eq!(tags.len(), 3);
+        assert_eq!(tags.get(b), Some(&2));
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let mut tags = TagMap::with_range(10, 11);
+        assert_eq!(tags.map('a'), Ok(10));
+        assert_eq!(tags.map('b'), Ok(11));
+        assert!(tags.is_exhausted());
+        assert_eq!(tags.map('c'), Err('c'));
+
+        tags.unmap(11);
+        assert!(!tags.is_exhausted());
+        assert_eq!(tags.map('c'), Ok(11));
+
+        let mut drained = tags.drain();
+        drained.sort();
+        assert_eq!(drained, vec![(10, 'a'), (11, 'c')]);
+        assert!(tags.is_empty());
+        assert_eq!(tags.map('d'), Ok(10));
+    }
+}
@@ -0,0 +1,411 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use super::handshake::{ClientHandshake, Feature, HandshakeError, Negotiated, Registry,
+                       ServerHandshake};
+use super::message::Message;
+use super::mux_framer::{read_message, write_message};
+
+pub const KEY_BUF: &[u8] = b"tls";
+
+/**
+ * How much a side of the session wants TLS, as advertised in the `tls`
+ * header.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Off,
+    Desired,
+    Required,
+}
+
+impl Level {
+    fn as_bytes(&self) -> &'static [u8] {
+        match *self {
+            Level::Off => b"off",
+            Level::Desired => b"desired",
+            Level::Required => b"required",
+        }
+    }
+
+    fn parse(buf: &[u8]) -> Option<Level> {
+        match buf {
+            b"off" => Some(Level::Off),
+            b"desired" => Some(Level::Desired),
+            b"required" => Some(Level::Required),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Negotiates opportunistic TLS. TLS is used when neither side has it off and
+ * at least one side asks for it; the handshake fails when one side requires
+ * it and the other has it off. A peer which does not send the header is
+ * treated as having TLS off. The agreed value is `required` when TLS is on.
+ */
+pub struct OppTls(pub Level);
+
+impl Feature for OppTls {
+    fn key(&self) -> &[u8] {
+        KEY_BUF
+    }
+
+    fn offer(&self) -> Option<Vec<u8>> {
+        Some(self.0.as_bytes().to_vec())
+    }
+
+    fn merge(&self, theirs: Option<&[u8]>) -> Result<Option<Vec<u8>>, HandshakeError> {
+        let theirs = match theirs {
+            Some(v) => Level::parse(v).ok_or_else(|| HandshakeError::BadHeader(KEY_BUF.to_vec()))?,
+            None => Level::Off,
+        };
+        let on = match (self.0, theirs) {
+            (Level::Off, Level::Required) |
+            (Level::Required, Level::Off) => {
+                return Err(HandshakeError::Incompatible(KEY_BUF.to_vec()))
+            }
+            (Level::Off, _) | (_, Level::Off) => false,
+            _ => true,
+        };
+        let agreed = if on { Level::Required } else { Level::Off };
+        Ok(Some(agreed.as_bytes().to_vec()))
+    }
+}
+
+/**
+ * Returns whether the session agreed on upgrading to TLS.
+ */
+pub fn is_negotiated(negotiated: &Negotiated) -> bool {
+    negotiated.get(KEY_BUF) == Some(Level::Required.as_bytes())
+}
+
+fn invalid_data<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/**
+ * Loads a client configuration trusting the PEM encoded certificates in
+ * `ca_path`.
+ */
+pub fn load_client_config<P: AsRef<Path>>(ca_path: P) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    let mut rdr = BufReader::new(File::open(ca_path)?);
+    for cert in rustls_pemfile::certs(&mut rdr) {
+        roots.add(cert?).map_err(invalid_data)?;
+    }
+    let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/**
+ * Loads a server configuration presenting the PEM encoded certificate chain
+ * in `cert_path`, signed with the PEM encoded private key in `key_path`.
+ */
+pub fn load_server_config<P, Q>(cert_path: P, key_path: Q) -> io::Result<Arc<ServerConfig>>
+    where P: AsRef<Path>,
+          Q: AsRef<Path>
+{
+    let mut rdr = BufReader::new(File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut rdr).collect::<Result<Vec<_>, _>>()?;
+    let mut rdr = BufReader::new(File::open(key_path)?);
+    let key = rustls_pemfile::private_key(&mut rdr)?
+        .ok_or_else(|| invalid_data("no private key found"))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/**
+ * The TLS settings of a client session.
+ */
+pub struct ClientTls {
+    pub level: Level,
+    pub config: Arc<ClientConfig>,
+    /** The name the server's certificate is verified against. */
+    pub server_name: ServerName<'static>,
+}
+
+/**
+ * The TLS settings of a server session.
+ */
+pub struct ServerTls {
+    pub level: Level,
+    pub config: Arc<ServerConfig>,
+}
+
+/**
+ * The stream of a session, which is either the plain underlying stream or
+ * that stream wrapped in TLS.
+ */
+pub enum Stream<S> {
+    Plain(S),
+    Tls(Box<TlsStream<S>>),
+}
+
+impl<S> Stream<S> {
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            Stream::Plain(_) => false,
+            Stream::Tls(_) => true,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(self: Pin<&mut Self>,
+                 cx: &mut Context<'_>,
+                 buf: &mut ReadBuf<'_>)
+                 -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            Stream::Plain(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(self: Pin<&mut Self>,
+                  cx: &mut Context<'_>,
+                  buf: &[u8])
+                  -> Poll<io::Result<usize>> {
+        match *self.get_mut() {
+            Stream::Plain(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            Stream::Plain(ref mut s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            Stream::Plain(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(ref mut s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/**
+ * Opens a session over `stream`: sends our `Tinit` offering the features in
+ * `registry` (plus TLS, if configured), waits for the answer and upgrades the
+ * stream to TLS before any further frame if that was agreed.
+ */
+pub async fn connect<S>(mut stream: S,
+                        mut registry: Registry,
+                        tls: Option<ClientTls>)
+                        -> io::Result<(Stream<S>, Negotiated)>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    if let Some(ref tls) = tls {
+        registry.register(OppTls(tls.level));
+    }
+    let mut handshake = ClientHandshake::new(registry);
+    write_message(&mut stream, &handshake.tinit()).await?;
+    let negotiated = handshake.receive(read_message(&mut stream).await?).map_err(invalid_data)?;
+
+    match tls {
+        Some(tls) if is_negotiated(&negotiated) => {
+            debug!("upgrading session to TLS");
+            let connector = TlsConnector::from(tls.config);
+            let stream = connector.connect(tls.server_name, stream).await?;
+            Ok((Stream::Tls(Box::new(stream.into())), negotiated))
+        }
+        Some(ref tls) if tls.level == Level::Required => {
+            Err(invalid_data(HandshakeError::Incompatible(KEY_BUF.to_vec())))
+        }
+        _ => Ok((Stream::Plain(stream), negotiated)),
+    }
+}
+
+/**
+ * Accepts a session over `stream`: answers the client's `Tinit` with the
+ * features in `registry` (plus TLS, if configured) and upgrades the stream to
+ * TLS before any further frame if that was agreed. For a legacy client, which
+ * doesn't send `Tinit`, its first message is returned so that it can be
+ * processed as usual, e.g. by passing it to `Connection::with_pending`.
+ */
+pub async fn accept<S>(mut stream: S,
+                       mut registry: Registry,
+                       tls: Option<ServerTls>)
+                       -> io::Result<(Stream<S>, Negotiated, Option<Message>)>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    if let Some(ref tls) = tls {
+        registry.register(OppTls(tls.level));
+    }
+    let mut handshake = ServerHandshake::new(registry);
+    let first = read_message(&mut stream).await?;
+    let (negotiated, rinit) = match handshake.receive(&first) {
+        Ok(res) => res,
+        Err(e) => {
+            write_message(&mut stream, &e.to_rerr(first.tag())).await?;
+            return Err(invalid_data(e));
+        }
+    };
+    let rinit = match rinit {
+        Some(rinit) => rinit,
+        None => {
+            if let Some(ServerTls { level: Level::Required, .. }) = tls {
+                return Err(invalid_data(HandshakeError::Incompatible(KEY_BUF.to_vec())));
+            }
+            return Ok((Stream::Plain(stream), negotiated, Some(first)));
+        }
+    };
+    write_message(&mut stream, &rinit).await?;
+
+    match tls {
+        Some(tls) if is_negotiated(&negotiated) => {
+            debug!("upgrading session to TLS");
+            let acceptor = TlsAcceptor::from(tls.config);
+            let stream = acceptor.accept(stream).await?;
+            Ok((Stream::Tls(Box::new(stream.into())), negotiated, None))
+        }
+        _ => Ok((Stream::Plain(stream), negotiated, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::future;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Framed;
+
+    use rustls::pki_types::ServerName;
+    use rustls::server::ResolvesServerCertUsingSni;
+
+    use super::*;
+    use crate::rpc::{Request, Response};
+    use crate::server::serve;
+    use crate::transport::mux_framer::MuxCodec;
+
+    #[test]
+    fn test_merge() {
+        let merge = |ours, theirs: Option<Level>| OppTls(ours).merge(theirs.map(|l| l.as_bytes()));
+        let on = Ok(Some(b"required".to_vec()));
+        let off = Ok(Some(b"off".to_vec()));
+        assert_eq!(merge(Level::Desired, Some(Level::Desired)), on);
+        assert_eq!(merge(Level::Desired, Some(Level::Required)), on);
+        assert_eq!(merge(Level::Desired, Some(Level::Off)), off);
+        assert_eq!(merge(Level::Desired, None), off);
+        assert_eq!(merge(Level::Off, Some(Level::Off)), off);
+        assert_eq!(merge(Level::Required, None),
+                   Err(HandshakeError::Incompatible(KEY_BUF.to_vec())));
+        assert_eq!(OppTls(Level::Desired).merge(Some(b"maybe")),
+                   Err(HandshakeError::BadHeader(KEY_BUF.to_vec())));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_over_loopback() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("mux-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, cert.cert.pem()).unwrap();
+        fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+
+        let server_config = load_server_config(&cert_path, &key_path).unwrap();
+        let client_config = load_client_config(&cert_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let tls = ServerTls {
+                level: Level::Desired,
+                config: server_config,
+            };
+            let (mut stream, negotiated, first) =
+                accept(sock, Registry::new(), Some(tls)).await.unwrap();
+            assert!(is_negotiated(&negotiated));
+            assert!(first.is_none());
+            assert!(stream.is_tls());
+            match read_message(&mut stream).await.unwrap() {
+                Message::Tping { tag } => {
+                    write_message(&mut stream, &Message::Rping { tag }).await.unwrap()
+                }
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        });
+
+        let tls = ClientTls {
+            level: Level::Required,
+            config: client_config,
+            server_name: ServerName::try_from("localhost").unwrap(),
+        };
+        let sock = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, negotiated) = connect(sock, Registry::new(), Some(tls)).await.unwrap();
+        assert!(is_negotiated(&negotiated));
+        assert!(stream.is_tls());
+        write_message(&mut stream, &Message::Tping { tag: 7 }).await.unwrap();
+        assert_eq!(read_message(&mut stream).await.unwrap(), Message::Rping { tag: 7 });
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mismatch() {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            // The handshake fails before any certificate is needed.
+            let certs = Arc::new(ResolvesServerCertUsingSni::new());
+            let tls = ServerTls {
+                level: Level::Required,
+                config: Arc::new(ServerConfig::builder()
+                                     .with_no_client_auth()
+                                     .with_cert_resolver(certs)),
+            };
+            accept(theirs, Registry::new(), Some(tls)).await.map(|_| ())
+        });
+
+        // The client has TLS off, so it must not mistake the rejection for a legacy server.
+        let err = connect(ours, Registry::new(), None).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "handshake rejected: incompatible value for header tls");
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_legacy() {
+        let (mut ours, theirs) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let (stream, negotiated, first) = accept(theirs, Registry::new(), None).await.unwrap();
+            assert!(negotiated.is_legacy());
+            let echo = |req: Request| future::ok(Response::new(req.body));
+            serve(Framed::new(stream, MuxCodec::new()), echo)
+                .with_pending(first.unwrap())
+                .run()
+                .await
+        });
+
+        // A legacy client dispatches right away, without a Tinit.
+        let tdispatch = Message::tdispatch(2, "/echo".parse().unwrap(), vec![1]);
+        write_message(&mut ours, &tdispatch).await.unwrap();
+        assert_eq!(read_message(&mut ours).await.unwrap(), Message::rdispatch_ok(2, vec![1]));
+        write_message(&mut ours, &Message::Treq { tag: 3, req: vec![2] }).await.unwrap();
+        assert_eq!(read_message(&mut ours).await.unwrap(),
+                   Message::RreqOk { tag: 3, reply: vec![2] });
+
+        drop(ours);
+        server.await.unwrap().unwrap();
+    }
+}